@@ -0,0 +1,491 @@
+//! Consistency check of the index database.
+//!
+//! The spans recorded in the `span` tree are compared with the keys in the other trees. Optionally
+//! a sample of blocks is re-indexed from the node and compared with the stored keys. Unfinalized
+//! blocks indexed from the best chain are not in any span and are not reported as orphans. The
+//! indexer must not be writing to the database while it is checked.
+
+use num_format::{Locale, ToFormattedString};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use subxt::{backend::legacy::LegacyRpcMethods, OnlineClient};
+use tracing::{info, warn};
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::{
+    migration::rebuild_count,
+    open_trees,
+    shared::*,
+    storage::{Batch, Db, Tree},
+    substrate::Indexer,
+};
+
+/// Options for the consistency check
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Number of indexed blocks to re-index from the node and compare with the stored keys
+    pub sample: u32,
+    /// Fix the problems that are found
+    pub repair: bool,
+}
+
+/// Problem found by the consistency check
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Finding {
+    /// Span entry that cannot be decoded or ends before it starts
+    CorruptSpan { key: String },
+    /// Spans that contain some of the same blocks
+    OverlappingSpans { first: Span, second: Span },
+    /// Key that is too short to contain a block number and event index
+    CorruptKey { tree: String, key: String },
+    /// Keys of blocks that are not in any span
+    OrphanedKeys {
+        tree: String,
+        keys: u64,
+        first_block: u32,
+        last_block: u32,
+    },
+    /// Event counts that do not match the index trees
+    CountMismatch { keys: u64 },
+    /// Keys produced by re-indexing a block that are not stored
+    MissingKeys {
+        tree: String,
+        block_number: u32,
+        keys: u64,
+    },
+    /// Stored keys that are not produced by re-indexing a block
+    UnexpectedKeys {
+        tree: String,
+        block_number: u32,
+        keys: u64,
+    },
+    /// Stored block hash that differs from the block hash on the node
+    BlockHashMismatch { block_number: u32 },
+}
+
+/// Span entry with the indexer version and settings it was indexed with
+#[derive(Debug, Clone)]
+struct SpanEntry {
+    span: Span,
+    version: u16,
+    index_variant: u8,
+}
+
+/// Determines if a block is in one of the sorted, non-overlapping spans.
+fn is_covered(spans: &[SpanEntry], block_number: u32) -> bool {
+    let i = spans.partition_point(|entry| entry.span.end < block_number);
+    spans
+        .get(i)
+        .is_some_and(|entry| entry.span.start <= block_number)
+}
+
+/// Determines if a block is in one of the spans or is an unfinalized block indexed from the best
+/// chain. Unfinalized blocks are not in any span until they are finalized.
+fn is_indexed(spans: &[SpanEntry], unfinalized: &BTreeSet<u32>, block_number: u32) -> bool {
+    is_covered(spans, block_number) || unfinalized.contains(&block_number)
+}
+
+/// Selects blocks evenly spread over the spans.
+fn sample_blocks(spans: &[SpanEntry], sample: u32) -> BTreeSet<u32> {
+    let total: u64 = spans
+        .iter()
+        .map(|entry| u64::from(entry.span.end - entry.span.start) + 1)
+        .sum();
+    let sample = u64::from(sample).min(total);
+    (0..sample)
+        .filter_map(|i| {
+            let mut offset = i * total / sample;
+            for entry in spans {
+                let len = u64::from(entry.span.end - entry.span.start) + 1;
+                if offset < len {
+                    return Some(entry.span.start + u32::try_from(offset).unwrap());
+                }
+                offset -= len;
+            }
+            None
+        })
+        .collect()
+}
+
+fn tree_name(tree: &Tree) -> String {
+    String::from_utf8_lossy(&tree.name()).into_owned()
+}
+
+/// Reads the span tree. Returns the valid spans sorted by start block and the keys of corrupt
+/// entries.
+fn read_spans(span_db: &Tree) -> Result<(Vec<SpanEntry>, Vec<Vec<u8>>), IndexError> {
+    let mut spans = Vec::new();
+    let mut corrupt = Vec::new();
+    for item in span_db {
+        let (key, value) = item?;
+        let entry = match (
+            <[u8; 4]>::try_from(key.as_slice()),
+            SpanDbValue::read_from(&value),
+        ) {
+            (Ok(end), Some(value)) => Some(SpanEntry {
+                span: Span {
+                    start: value.start.into(),
+                    end: u32::from_be_bytes(end),
+                },
+                version: value.version.into(),
+                index_variant: value.index_variant,
+            }),
+            _ => None,
+        };
+        match entry {
+            Some(entry) if entry.span.start <= entry.span.end => spans.push(entry),
+            _ => corrupt.push(key),
+        }
+    }
+    spans.sort_by_key(|entry| entry.span.start);
+    Ok((spans, corrupt))
+}
+
+/// Merges overlapping spans. A merged span keeps the oldest version and settings so it will be
+/// re-indexed if either span would have been.
+fn merge_spans(spans: Vec<SpanEntry>) -> Vec<SpanEntry> {
+    let mut merged: Vec<SpanEntry> = Vec::new();
+    for entry in spans {
+        match merged.last_mut() {
+            Some(last) if entry.span.start <= last.span.end => {
+                last.span.end = last.span.end.max(entry.span.end);
+                last.version = last.version.min(entry.version);
+                last.index_variant = last.index_variant.min(entry.index_variant);
+            }
+            _ => merged.push(entry),
+        }
+    }
+    merged
+}
+
+/// Removes a block from the spans so it will be indexed again.
+fn remove_block(spans: Vec<SpanEntry>, block_number: u32) -> Vec<SpanEntry> {
+    let mut result = Vec::new();
+    for entry in spans {
+        if block_number < entry.span.start || block_number > entry.span.end {
+            result.push(entry);
+            continue;
+        }
+        if entry.span.start < block_number {
+            result.push(SpanEntry {
+                span: Span {
+                    start: entry.span.start,
+                    end: block_number - 1,
+                },
+                ..entry.clone()
+            });
+        }
+        if block_number < entry.span.end {
+            result.push(SpanEntry {
+                span: Span {
+                    start: block_number + 1,
+                    end: entry.span.end,
+                },
+                ..entry
+            });
+        }
+    }
+    result
+}
+
+/// Replaces the span tree with `spans` in a single batch.
+fn write_spans(span_db: &Tree, spans: &[SpanEntry]) -> Result<(), IndexError> {
+    let ends: BTreeSet<[u8; 4]> = spans
+        .iter()
+        .map(|entry| entry.span.end.to_be_bytes())
+        .collect();
+    let mut batch = Batch::default();
+    for key in span_db.iter().keys() {
+        let key = key?;
+        if !<[u8; 4]>::try_from(key.as_slice()).is_ok_and(|end| ends.contains(&end)) {
+            batch.remove(key);
+        }
+    }
+    for entry in spans {
+        let value = SpanDbValue {
+            start: entry.span.start.into(),
+            version: entry.version.into(),
+            index_variant: entry.index_variant,
+        };
+        batch.insert(entry.span.end.to_be_bytes(), value.as_bytes());
+    }
+    span_db.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Keys of each index tree for each block
+type BlockKeys = HashMap<(String, u32), BTreeSet<Vec<u8>>>;
+
+/// Re-indexes blocks from the node into an in-memory database.
+async fn fetch_blocks<R: RuntimeIndexer>(
+    api: OnlineClient<R::RuntimeConfig>,
+    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    blocks: &BTreeSet<u32>,
+) -> Result<(Trees<<R::ChainKey as IndexKey>::ChainTrees>, BlockKeys), IndexError> {
+    let trees = open_trees::<R>(Db::memory())?;
+    let indexer = Indexer::<R>::new(trees.clone(), api, rpc, true, false);
+    for block_number in blocks {
+        indexer.index_block(*block_number).await?;
+    }
+    let mut keys = BlockKeys::new();
+    for tree in trees.index_trees() {
+        for key in tree.iter().keys() {
+            let key = key?;
+            if let Some(block_number) = event_key_block_number(&key) {
+                keys.entry((tree_name(tree), block_number))
+                    .or_default()
+                    .insert(key);
+            }
+        }
+    }
+    Ok((trees, keys))
+}
+
+/// Checks the consistency of the database and returns the problems found. If a node is provided,
+/// `options.sample` blocks are re-indexed and compared with the stored keys.
+#[allow(clippy::type_complexity)]
+pub async fn fsck<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    node: Option<(
+        OnlineClient<R::RuntimeConfig>,
+        LegacyRpcMethods<R::RuntimeConfig>,
+    )>,
+    options: &FsckOptions,
+) -> Result<Vec<Finding>, IndexError> {
+    let mut findings = Vec::new();
+    // Check the spans.
+    let (spans, corrupt_spans) = read_spans(&trees.span)?;
+    for key in &corrupt_spans {
+        findings.push(Finding::CorruptSpan {
+            key: format!("0x{}", hex::encode(key)),
+        });
+    }
+    for pair in spans.windows(2) {
+        if pair[1].span.start <= pair[0].span.end {
+            findings.push(Finding::OverlappingSpans {
+                first: pair[0].span.clone(),
+                second: pair[1].span.clone(),
+            });
+        }
+    }
+    let mut spans = merge_spans(spans);
+    let unfinalized = trees
+        .unfinalized
+        .iter()
+        .keys()
+        .map(|key| Ok(u32::from_be_bytes(key?.as_slice().try_into().unwrap())))
+        .collect::<Result<BTreeSet<u32>, IndexError>>()?;
+    // Re-index a sample of blocks.
+    let sample = sample_blocks(&spans, options.sample);
+    let fetched = match node {
+        Some((api, rpc)) if !sample.is_empty() => {
+            info!(
+                "🔍 Re-indexing {} sample blocks",
+                sample.len().to_formatted_string(&Locale::en)
+            );
+            Some(fetch_blocks::<R>(api, rpc, &sample).await?)
+        }
+        _ => None,
+    };
+    // Check the keys in the index trees.
+    let mut stored = BlockKeys::new();
+    let mut corrupt_keys = Vec::new();
+    let mut count_mismatches = 0;
+    let mut count_prefixes = 0;
+    for tree in trees.index_trees() {
+        let name = tree_name(tree);
+        info!("🔍 Checking tree {}", name);
+        let mut orphaned: Option<(u64, u32, u32)> = None;
+        let mut prefix: Option<(Vec<u8>, u64)> = None;
+        let mut check_count = |prefix: Option<(Vec<u8>, u64)>| -> Result<(), IndexError> {
+            if let Some((prefix, event_count)) = prefix {
+                count_prefixes += 1;
+                let stored_count = trees
+                    .count
+                    .get(count_db_key(tree, &prefix))?
+                    .map(|value| u64::from_be_bytes(value.as_slice().try_into().unwrap()));
+                if stored_count != Some(event_count) {
+                    count_mismatches += 1;
+                }
+            }
+            Ok(())
+        };
+        for key in tree.iter().keys() {
+            let key = key?;
+            let Some(block_number) = event_key_block_number(&key) else {
+                findings.push(Finding::CorruptKey {
+                    tree: name.clone(),
+                    key: format!("0x{}", hex::encode(&key)),
+                });
+                corrupt_keys.push((tree, key));
+                continue;
+            };
+            if !is_indexed(&spans, &unfinalized, block_number) {
+                orphaned = Some(match orphaned {
+                    None => (1, block_number, block_number),
+                    Some((keys, first, last)) => {
+                        (keys + 1, first.min(block_number), last.max(block_number))
+                    }
+                });
+            }
+            if sample.contains(&block_number) {
+                stored
+                    .entry((name.clone(), block_number))
+                    .or_default()
+                    .insert(key.clone());
+            }
+            // Keys are sorted by prefix, so each prefix is counted in a single run.
+            let key_prefix = &key[..key.len() - 6];
+            match &mut prefix {
+                Some((prefix, event_count)) if prefix == key_prefix => *event_count += 1,
+                _ => check_count(prefix.replace((key_prefix.to_vec(), 1)))?,
+            }
+        }
+        check_count(prefix)?;
+        if let Some((keys, first_block, last_block)) = orphaned {
+            findings.push(Finding::OrphanedKeys {
+                tree: name,
+                keys,
+                first_block,
+                last_block,
+            });
+        }
+    }
+    // Every count must belong to a prefix that was found.
+    let count_mismatches =
+        count_mismatches + (trees.count.len() as u64).saturating_sub(count_prefixes);
+    if count_mismatches != 0 {
+        findings.push(Finding::CountMismatch {
+            keys: count_mismatches,
+        });
+    }
+    // Check the trees keyed by block number.
+    for tree in [&trees.payload, &trees.block_hash] {
+        let mut orphaned: Option<(u64, u32, u32)> = None;
+        for key in tree.iter().keys() {
+            let key = key?;
+            let Some(block_number) = key.first_chunk::<4>().map(|key| u32::from_be_bytes(*key))
+            else {
+                findings.push(Finding::CorruptKey {
+                    tree: tree_name(tree),
+                    key: format!("0x{}", hex::encode(&key)),
+                });
+                corrupt_keys.push((tree, key));
+                continue;
+            };
+            if !is_indexed(&spans, &unfinalized, block_number) {
+                orphaned = Some(match orphaned {
+                    None => (1, block_number, block_number),
+                    Some((keys, first, last)) => {
+                        (keys + 1, first.min(block_number), last.max(block_number))
+                    }
+                });
+            }
+        }
+        if let Some((keys, first_block, last_block)) = orphaned {
+            findings.push(Finding::OrphanedKeys {
+                tree: tree_name(tree),
+                keys,
+                first_block,
+                last_block,
+            });
+        }
+    }
+    // Compare the sample blocks with the node.
+    let mut requeue = BTreeSet::new();
+    if let Some((fetched_trees, fetched)) = &fetched {
+        let variant_name = tree_name(&trees.variant);
+        for block_number in &sample {
+            let stored_hash = trees.block_hash.get(block_number.to_be_bytes())?;
+            if stored_hash.is_some()
+                && stored_hash != fetched_trees.block_hash.get(block_number.to_be_bytes())?
+            {
+                findings.push(Finding::BlockHashMismatch {
+                    block_number: *block_number,
+                });
+                requeue.insert(*block_number);
+            }
+            let index_variant = spans
+                .iter()
+                .find(|entry| entry.span.start <= *block_number && *block_number <= entry.span.end)
+                .is_some_and(|entry| entry.index_variant == 1);
+            for tree in trees.index_trees() {
+                let name = tree_name(tree);
+                // Variants are only indexed in some spans.
+                if name == variant_name && !index_variant {
+                    continue;
+                }
+                let empty = BTreeSet::new();
+                let key = (name.clone(), *block_number);
+                let fetched_keys = fetched.get(&key).unwrap_or(&empty);
+                let stored_keys = stored.get(&key).unwrap_or(&empty);
+                let missing = fetched_keys.difference(stored_keys).count() as u64;
+                if missing != 0 {
+                    findings.push(Finding::MissingKeys {
+                        tree: name.clone(),
+                        block_number: *block_number,
+                        keys: missing,
+                    });
+                    requeue.insert(*block_number);
+                }
+                let unexpected = stored_keys.difference(fetched_keys).count() as u64;
+                if unexpected != 0 {
+                    findings.push(Finding::UnexpectedKeys {
+                        tree: name,
+                        block_number: *block_number,
+                        keys: unexpected,
+                    });
+                    requeue.insert(*block_number);
+                }
+            }
+        }
+    }
+    for finding in &findings {
+        warn!("🔍 {:?}", finding);
+    }
+    info!(
+        "🔍 Found {} problems",
+        findings.len().to_formatted_string(&Locale::en)
+    );
+    if !options.repair || findings.is_empty() {
+        return Ok(findings);
+    }
+    // Re-queue blocks by removing them from the spans. Their keys are removed with the orphans.
+    for block_number in &requeue {
+        spans = remove_block(spans, *block_number);
+    }
+    write_spans(&trees.span, &spans)?;
+    for (tree, key) in corrupt_keys {
+        tree.remove(key)?;
+    }
+    let counts = trees.lock_counts();
+    for tree in trees.index_trees() {
+        remove_event_keys(tree, &trees.count, |block_number| {
+            !is_indexed(&spans, &unfinalized, block_number)
+        })?;
+    }
+    for tree in [&trees.payload, &trees.block_hash] {
+        let mut batch = Batch::default();
+        for key in tree.iter().keys() {
+            let key = key?;
+            let Some(block_number) = block_number_at(&key, 0) else {
+                continue;
+            };
+            if !is_indexed(&spans, &unfinalized, block_number) {
+                batch.remove(key);
+            }
+        }
+        tree.apply_batch(batch)?;
+    }
+    if count_mismatches != 0 {
+        rebuild_count(trees)?;
+    }
+    drop(counts);
+    info!("🔍 Repaired database");
+    Ok(findings)
+}
@@ -0,0 +1,196 @@
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{convert::Infallible, net::SocketAddr};
+use subxt::backend::legacy::LegacyRpcMethods;
+use tokio::sync::{mpsc::UnboundedSender, watch::Receiver};
+use tracing::{error, info};
+
+use crate::{shared::*, websockets::*};
+
+/// Converts a URL query string into request fields. Values that are valid JSON are parsed as JSON.
+pub fn query_params(query: Option<&str>) -> Map<String, Value> {
+    form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .map(|(name, value)| {
+            let value = serde_json::from_str(&value).unwrap_or(Value::String(value.into_owned()));
+            (name.into_owned(), value)
+        })
+        .collect()
+}
+
+/// Fields of a re-index request
+#[derive(Deserialize)]
+struct ReindexParams {
+    start: u32,
+    end: u32,
+}
+
+/// Converts the result of a request into the status code and response message.
+fn http_response<CK: IndexKey>(
+    response_msg: Result<ResponseMessage<CK>, IndexError>,
+) -> (StatusCode, ResponseMessage<CK>) {
+    match response_msg {
+        Ok(response_msg) => (StatusCode::OK, response_msg),
+        Err(error) => {
+            error!("{}", error);
+            let status = match ErrorCode::from(&error) {
+                ErrorCode::Parse | ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, error.into())
+        }
+    }
+}
+
+/// Processes an HTTP request and returns the status code and response message.
+pub async fn process_http_request<R: RuntimeIndexer>(
+    rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+) -> Option<(StatusCode, ResponseMessage<R::ChainKey>)> {
+    if method != Method::GET {
+        return None;
+    }
+    let response_msg = match path {
+        "/status" => Ok(process_msg_status::<R>(trees)),
+        "/target" => process_msg_target::<R>(trees),
+        "/variants" => process_msg_variants::<R>(rpc).await,
+        "/events" => match request_from_params("GetEvents", query_params(query)) {
+            Ok(RequestMessage::GetEvents { key, query }) => {
+                process_msg_get_events::<R>(trees, key, query)
+            }
+            Ok(_) => unreachable!(),
+            Err(error) => Err(error),
+        },
+        "/size-on-disk" => trees
+            .root
+            .size_on_disk()
+            .map(ResponseMessage::SizeOnDisk)
+            .map_err(IndexError::from),
+        "/tree-stats" => process_msg_tree_stats::<R>(trees),
+        "/failed-blocks" => process_msg_failed_blocks::<R>(trees),
+        _ => return None,
+    };
+    Some(http_response(response_msg))
+}
+
+/// Processes a request to the admin port and returns the status code and response message.
+pub fn process_admin_request<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+) -> Option<(StatusCode, ResponseMessage<R::ChainKey>)> {
+    let response_msg = match (method, path) {
+        (&Method::POST, "/reindex") => {
+            match serde_json::from_value(Value::Object(query_params(query))) {
+                Ok(ReindexParams { start, end }) => {
+                    process_msg_reindex_blocks::<R>(trees, start, end, sub_tx)
+                }
+                Err(error) => Err(error.into()),
+            }
+        }
+        _ => return None,
+    };
+    Some(http_response(response_msg))
+}
+
+/// Converts a response into an HTTP response. Requests without a response are not found.
+pub fn hyper_response<CK: IndexKey + Serialize>(
+    response: Option<(StatusCode, ResponseMessage<CK>)>,
+) -> hyper::Response<Body> {
+    match response {
+        Some((status, response_msg)) => hyper::Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&response_msg).unwrap()))
+            .unwrap(),
+        None => hyper::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+async fn handle_request<R: RuntimeIndexer>(
+    rpc_rx: Receiver<LegacyRpcMethods<R::RuntimeConfig>>,
+    trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    // Use the most recent connection to the node.
+    let rpc = rpc_rx.borrow().clone();
+    let uri = request.uri();
+    let response =
+        process_http_request::<R>(&rpc, &trees, request.method(), uri.path(), uri.query()).await;
+    Ok(hyper_response(response))
+}
+
+pub async fn http_listen<R: RuntimeIndexer + 'static>(
+    trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    rpc_rx: Receiver<LegacyRpcMethods<R::RuntimeConfig>>,
+    port: u16,
+    mut exit_rx: Receiver<bool>,
+) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(move |_| {
+        let rpc_rx = rpc_rx.clone();
+        let trees = trees.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request::<R>(rpc_rx.clone(), trees.clone(), request)
+            }))
+        }
+    });
+    let server = Server::bind(&addr).serve(make_service);
+    info!("HTTP listening on: {}", addr);
+
+    let server = server.with_graceful_shutdown(async {
+        let _ = exit_rx.changed().await;
+    });
+    if let Err(error) = server.await {
+        error!("HTTP server error: {}", error);
+    }
+}
+
+/// Listens for admin requests. Only local clients can connect.
+pub async fn admin_listen<R: RuntimeIndexer + 'static>(
+    trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    port: u16,
+    mut exit_rx: Receiver<bool>,
+    sub_tx: UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let make_service = make_service_fn(move |_| {
+        let trees = trees.clone();
+        let sub_tx = sub_tx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                let uri = request.uri();
+                let response = process_admin_request::<R>(
+                    &trees,
+                    &sub_tx,
+                    request.method(),
+                    uri.path(),
+                    uri.query(),
+                );
+                async move { Ok::<_, Infallible>(hyper_response(response)) }
+            }))
+        }
+    });
+    let server = Server::bind(&addr).serve(make_service);
+    info!("Admin listening on: {}", addr);
+
+    let server = server.with_graceful_shutdown(async {
+        let _ = exit_rx.changed().await;
+    });
+    if let Err(error) = server.await {
+        error!("Admin server error: {}", error);
+    }
+}
@@ -26,7 +26,7 @@ use crate::{
     shared::*, substrate::Indexer, IndexError,
 };
 use serde::{Deserialize, Serialize};
-use sled::{Db, Tree};
+use crate::storage::{Db, StorageError, Tree};
 use std::hash::Hash;
 
 // ChainKey implementation for IDN-specific keys, if needed
@@ -49,7 +49,7 @@ pub struct IdnChainTrees {
 }
 
 impl IndexTrees for IdnChainTrees {
-    fn open(db: &Db) -> Result<Self, sled::Error> {
+    fn open(db: &Db) -> Result<Self, StorageError> {
         Ok(IdnChainTrees {
             // Open your custom trees here
             // For example:
@@ -58,13 +58,20 @@ impl IndexTrees for IdnChainTrees {
         })
     }
 
-    fn flush(&self) -> Result<(), sled::Error> {
+    fn flush(&self) -> Result<(), StorageError> {
         // Flush your custom trees here
         // For example:
         // self.custom_subscription_tree.flush()?;
         // self.custom_beacon_tree.flush()?;
         Ok(())
     }
+
+    fn trees(&self) -> Vec<&Tree> {
+        // List your custom trees here
+        // For example:
+        // vec![&self.custom_subscription_tree, &self.custom_beacon_tree]
+        vec![]
+    }
 }
 
 impl IndexKey for IdnChainKey {
@@ -73,9 +80,10 @@ impl IndexKey for IdnChainKey {
     fn write_db_key(
         &self,
         trees: &IdnChainTrees,
+        batch: &mut IndexBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) -> Result<(), StorageError> {
         match self {
             // Implement indexing for your custom keys here
             // For example:
@@ -85,19 +93,37 @@ impl IndexKey for IdnChainKey {
             //         block_number: block_number.into(),
             //         event_index: event_index.into(),
             //     };
-            //     trees.custom_subscription_tree.insert(key.as_bytes(), &[])?
+            //     write_event_key(&trees.custom_subscription_tree, batch, key.as_bytes())
             // }
         };
         Ok(())
     }
 
-    fn get_key_events(&self, trees: &IdnChainTrees) -> Vec<Event> {
+    fn get_key_events(
+        &self,
+        trees: &IdnChainTrees,
+        query: &EventQuery,
+    ) -> Result<Vec<Event>, StorageError> {
         match self {
             // Implement event retrieval for your custom keys here
             // For example:
             // IdnChainKey::CustomSubscriptionId(id) => {
-            //     // Logic to get events for this key
-            //     Vec::new() // Replace with actual implementation
+            //     get_events_u32(&trees.custom_subscription_tree, *id, query)
+            // }
+        }
+    }
+
+    fn count_key_events(
+        &self,
+        trees: &IdnChainTrees,
+        count: &Tree,
+        range: &BlockRange,
+    ) -> Result<u64, IndexError> {
+        match self {
+            // Implement event counting for your custom keys here
+            // For example:
+            // IdnChainKey::CustomSubscriptionId(id) => {
+            //     count_events(&trees.custom_subscription_tree, count, &id.to_be_bytes(), range)
             // }
         }
     }
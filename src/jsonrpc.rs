@@ -0,0 +1,331 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use subxt::backend::legacy::LegacyRpcMethods;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::error;
+
+use crate::{shared::*, websockets::*};
+
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+
+/// JSON-RPC 2.0 request
+#[derive(Deserialize, Debug, Clone)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    /// Requests without an id are notifications and do not get a response.
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub id: Option<Option<RequestId>>,
+}
+
+/// Distinguishes a null id from a missing id.
+fn deserialize_id<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<RequestId>>, D::Error> {
+    Ok(Some(Option::deserialize(deserializer)?))
+}
+
+/// JSON-RPC 2.0 error object
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i32,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<ErrorCode>,
+}
+
+impl JsonRpcError {
+    fn new(code: i32, message: &str) -> Self {
+        JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<IndexError> for JsonRpcError {
+    fn from(error: IndexError) -> Self {
+        let data = ErrorCode::from(&error);
+        let code = match data {
+            ErrorCode::Database => -32000,
+            ErrorCode::Connection => -32001,
+            ErrorCode::BlockNotFound => -32002,
+            ErrorCode::Parse | ErrorCode::InvalidRequest => INVALID_PARAMS,
+        };
+        JsonRpcError {
+            code,
+            message: error.to_string(),
+            data: Some(data),
+        }
+    }
+}
+
+/// JSON-RPC 2.0 response
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+    pub id: Option<RequestId>,
+}
+
+impl JsonRpcResponse {
+    fn new(id: Option<RequestId>, result: Result<Value, JsonRpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        JsonRpcResponse {
+            jsonrpc: "2.0",
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+/// Subscription created with `index_subscribe`
+#[derive(Debug, Clone)]
+enum JsonRpcSubscription<CK: IndexKey> {
+    Status,
+    Events(Key<CK>),
+}
+
+/// JSON-RPC subscriptions of a connection
+pub struct JsonRpcSubscriptions<CK: IndexKey> {
+    next_id: u64,
+    subs: HashMap<u64, JsonRpcSubscription<CK>>,
+}
+
+impl<CK: IndexKey> Default for JsonRpcSubscriptions<CK> {
+    fn default() -> Self {
+        JsonRpcSubscriptions {
+            next_id: 1,
+            subs: HashMap::new(),
+        }
+    }
+}
+
+/// Determines if a message uses JSON-RPC rather than the native protocol.
+pub fn is_jsonrpc(value: &Value) -> bool {
+    match value {
+        Value::Object(object) => object.contains_key("jsonrpc"),
+        Value::Array(_) => true,
+        _ => false,
+    }
+}
+
+/// Determines if a text message that is not valid JSON was meant to be JSON-RPC.
+pub fn is_jsonrpc_text(text: &str) -> bool {
+    text.trim_start().starts_with('[') || text.contains("\"jsonrpc\"")
+}
+
+/// Response JSON to a JSON-RPC message that is not valid JSON.
+pub fn jsonrpc_parse_error() -> String {
+    let error = JsonRpcError::new(PARSE_ERROR, "parse error");
+    serde_json::to_string(&JsonRpcResponse::new(None, Err(error))).unwrap()
+}
+
+/// Converts the params of a method call into a native request message.
+pub fn request_msg<CK: IndexKey + for<'a> Deserialize<'a>>(
+    request_type: &str,
+    params: Value,
+) -> Result<RequestMessage<CK>, JsonRpcError> {
+    let object = match params {
+        Value::Null => Map::new(),
+        Value::Object(object) => object,
+        // Positional params contain a single object.
+        Value::Array(mut params) if params.len() <= 1 => match params.pop() {
+            None => Map::new(),
+            Some(Value::Object(object)) => object,
+            Some(_) => return Err(JsonRpcError::new(INVALID_PARAMS, "invalid params")),
+        },
+        _ => return Err(JsonRpcError::new(INVALID_PARAMS, "invalid params")),
+    };
+    Ok(request_from_params(request_type, object)?)
+}
+
+/// Converts a native response message into the result of a method call.
+pub fn response_result<CK: IndexKey + Serialize>(msg: ResponseMessage<CK>) -> Value {
+    match serde_json::to_value(msg).unwrap() {
+        Value::Object(mut object) => object.remove("data").unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+async fn process_method<R: RuntimeIndexer>(
+    rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    method: &str,
+    params: Value,
+    sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+    subs: &mut JsonRpcSubscriptions<R::ChainKey>,
+) -> Result<Value, JsonRpcError> {
+    let request_type = match method {
+        "index_status" => "Status",
+        "index_target" => "Target",
+        "index_variants" => "Variants",
+        "index_getEvents" => "GetEvents",
+        "index_query" => "Query",
+        "index_countEvents" => "CountEvents",
+        "index_getEventPayloads" => "GetEventPayloads",
+        "index_sizeOnDisk" => "SizeOnDisk",
+        "index_treeStats" => "TreeStats",
+        "index_failedBlocks" => "FailedBlocks",
+        "index_subscribe" => {
+            let mut params = match params {
+                Value::Array(params) if !params.is_empty() => params.into_iter(),
+                _ => return Err(JsonRpcError::new(INVALID_PARAMS, "invalid params")),
+            };
+            let id = subs.next_id;
+            let sub = match params.next().unwrap().as_str() {
+                Some("status") => {
+                    process_msg_subscribe_status::<R>(
+                        Some(RequestId::Number(id)),
+                        sub_tx,
+                        sub_response_tx,
+                    );
+                    JsonRpcSubscription::Status
+                }
+                Some("events") => {
+                    let params = params.next().unwrap_or(Value::Null);
+                    let RequestMessage::SubscribeEvents { key, range } =
+                        request_msg("SubscribeEvents", params)?
+                    else {
+                        unreachable!();
+                    };
+                    process_msg_subscribe_events::<R>(
+                        key.clone(),
+                        range,
+                        Some(RequestId::Number(id)),
+                        sub_tx,
+                        sub_response_tx,
+                    );
+                    JsonRpcSubscription::Events(key)
+                }
+                _ => return Err(JsonRpcError::new(INVALID_PARAMS, "invalid params")),
+            };
+            subs.next_id += 1;
+            subs.subs.insert(id, sub);
+            return Ok(id.into());
+        }
+        "index_unsubscribe" => {
+            let id = match params.get(0).and_then(Value::as_u64) {
+                Some(id) => id,
+                None => return Err(JsonRpcError::new(INVALID_PARAMS, "invalid params")),
+            };
+            let request_id = Some(RequestId::Number(id));
+            match subs.subs.remove(&id) {
+                Some(JsonRpcSubscription::Status) => {
+                    process_msg_unsubscribe_status::<R>(request_id, sub_tx, sub_response_tx);
+                }
+                Some(JsonRpcSubscription::Events(key)) => {
+                    process_msg_unsubscribe_events::<R>(key, request_id, sub_tx, sub_response_tx);
+                }
+                None => return Ok(false.into()),
+            };
+            return Ok(true.into());
+        }
+        _ => return Err(JsonRpcError::new(METHOD_NOT_FOUND, "method not found")),
+    };
+    let request = Request {
+        id: None,
+        msg: request_msg(request_type, params)?,
+    };
+    match process_msg::<R>(rpc, trees, request, sub_tx, sub_response_tx).await {
+        Ok(msg) => Ok(response_result(msg)),
+        Err(error) => {
+            error!("{}", error);
+            Err(error.into())
+        }
+    }
+}
+
+/// Processes a single JSON-RPC request. Notifications do not get a response.
+async fn process_jsonrpc_request<R: RuntimeIndexer>(
+    rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    request: Value,
+    sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+    subs: &mut JsonRpcSubscriptions<R::ChainKey>,
+) -> Option<JsonRpcResponse> {
+    let request = match serde_json::from_value::<JsonRpcRequest>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        _ => {
+            let error = JsonRpcError::new(INVALID_REQUEST, "invalid request");
+            return Some(JsonRpcResponse::new(None, Err(error)));
+        }
+    };
+    let result = process_method::<R>(
+        rpc,
+        trees,
+        &request.method,
+        request.params,
+        sub_tx,
+        sub_response_tx,
+        subs,
+    )
+    .await;
+    request.id.map(|id| JsonRpcResponse::new(id, result))
+}
+
+/// Processes a JSON-RPC request or batch of requests and returns the response JSON, if any.
+pub async fn process_jsonrpc<R: RuntimeIndexer>(
+    rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    request: Value,
+    sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+    subs: &mut JsonRpcSubscriptions<R::ChainKey>,
+) -> Option<String> {
+    match request {
+        Value::Array(requests) => {
+            if requests.is_empty() {
+                let error = JsonRpcError::new(INVALID_REQUEST, "invalid request");
+                return Some(
+                    serde_json::to_string(&JsonRpcResponse::new(None, Err(error))).unwrap(),
+                );
+            }
+            let mut responses = Vec::new();
+            for request in requests {
+                if let Some(response) =
+                    process_jsonrpc_request::<R>(rpc, trees, request, sub_tx, sub_response_tx, subs)
+                        .await
+                {
+                    responses.push(response);
+                }
+            }
+            match responses.is_empty() {
+                true => None,
+                false => Some(serde_json::to_string(&responses).unwrap()),
+            }
+        }
+        request => process_jsonrpc_request::<R>(rpc, trees, request, sub_tx, sub_response_tx, subs)
+            .await
+            .map(|response| serde_json::to_string(&response).unwrap()),
+    }
+}
+
+/// Converts a subscription notification into a JSON-RPC notification.
+pub fn jsonrpc_notification<CK: IndexKey + Serialize>(response: Response<CK>) -> String {
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "index_subscription",
+        "params": {
+            "subscription": response.id,
+            "result": response_result(response.msg),
+        },
+    });
+    notification.to_string()
+}
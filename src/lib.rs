@@ -12,24 +12,30 @@ use std::{
     process::exit,
     sync::{atomic::AtomicBool, Arc},
 };
-use subxt::{
-    backend::{legacy::LegacyRpcMethods, rpc::RpcClient},
-    OnlineClient,
-};
 use tokio::{
     join, spawn,
     sync::{mpsc, watch},
+    time,
 };
 use tracing::{error, info};
 use tracing_subscriber::filter::LevelFilter;
 
+pub mod fsck;
+pub mod http;
+pub mod jsonrpc;
+pub mod migration;
+pub mod query;
 pub mod shared;
+pub mod snapshot;
+pub mod storage;
 pub mod substrate;
 pub mod substrate_pallets;
 pub mod ideal_network_pallets;
 pub mod websockets;
 
 use crate::shared::*;
+use http::{admin_listen, http_listen};
+use storage::{Db, DbBackend, StorageError};
 use substrate::*;
 use websockets::websockets_listen;
 
@@ -37,41 +43,69 @@ use websockets::websockets_listen;
 mod tests;
 
 pub fn open_trees<R: RuntimeIndexer>(
-    db_config: sled::Config,
-) -> Result<Trees<<R::ChainKey as IndexKey>::ChainTrees>, sled::Error> {
-    let db = db_config.open()?;
+    db: Db,
+) -> Result<Trees<<R::ChainKey as IndexKey>::ChainTrees>, StorageError> {
     let trees = Trees {
         root: db.clone(),
         span: db.open_tree(b"span")?,
         variant: db.open_tree(b"variant")?,
+        count: db.open_tree(b"count")?,
+        payload: db.open_tree(b"payload")?,
+        block_hash: db.open_tree(b"block_hash")?,
+        unfinalized: db.open_tree(b"unfinalized")?,
+        failed: db.open_tree(b"failed")?,
         // Each event parameter to be indexed has its own tree.
         substrate: SubstrateTrees::open(&db)?,
         chain: <R::ChainKey as IndexKey>::ChainTrees::open(&db)?,
+        count_lock: Default::default(),
     };
+    // Refuse incompatible databases and upgrade older ones.
+    migration::migrate(&trees)?;
     Ok(trees)
 }
 
 pub fn close_trees<R: RuntimeIndexer>(
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-) -> Result<(), sled::Error> {
+) -> Result<(), StorageError> {
     info!("Closing db.");
     trees.root.flush()?;
     trees.span.flush()?;
     trees.variant.flush()?;
+    trees.count.flush()?;
+    trees.payload.flush()?;
+    trees.block_hash.flush()?;
+    trees.unfinalized.flush()?;
+    trees.failed.flush()?;
     trees.substrate.flush()?;
     Ok(())
 }
 
+/// Determines the default database path for chain `R`.
+pub fn default_db_path<R: RuntimeIndexer>() -> Option<PathBuf> {
+    let mut db_path = home::home_dir()?;
+    db_path.push(".local/share/acuity-index/");
+    db_path.push(R::get_name());
+    db_path.push("db");
+    Some(db_path)
+}
+
 /// Starts the indexer. Chain is defined by `R`.
 #[allow(clippy::too_many_arguments)]
 pub async fn start<R: RuntimeIndexer + 'static>(
     db_path: Option<String>,
+    db_backend: DbBackend,
     db_mode: sled::Mode,
     db_cache_capacity: u64,
-    url: Option<String>,
+    urls: Vec<String>,
     queue_depth: u8,
     index_variant: bool,
+    index_payload: bool,
+    index_best: bool,
+    retention: Option<u32>,
+    target: BlockRange,
     port: u16,
+    http_port: Option<u16>,
+    admin_port: Option<u16>,
     log_level: LevelFilter,
 ) {
     tracing_subscriber::fmt().with_max_level(log_level).init();
@@ -81,33 +115,35 @@ pub async fn start<R: RuntimeIndexer + 'static>(
     // Open database.
     let db_path = match db_path {
         Some(db_path) => PathBuf::from(db_path),
-        None => match home::home_dir() {
-            Some(mut db_path) => {
-                db_path.push(".local/share/acuity-index/");
-                db_path.push(name);
-                db_path.push("db");
-                db_path
-            }
+        None => match default_db_path::<R>() {
+            Some(db_path) => db_path,
             None => {
                 error!("No home directory.");
                 exit(1);
             }
         },
     };
-    info!("Database path: {}", db_path.display());
-    info!("Database mode: {:?}", db_mode);
-    info!(
-        "Database cache capacity: {}",
-        Byte::from_bytes(db_cache_capacity.into()).get_appropriate_unit(true)
-    );
-    let db_config = sled::Config::new()
-        .path(db_path)
-        .mode(db_mode)
-        .cache_capacity(db_cache_capacity);
-    let trees = match open_trees::<R>(db_config) {
+    info!("Database backend: {:?}", db_backend);
+    let db = match db_backend {
+        DbBackend::Sled => {
+            info!("Database path: {}", db_path.display());
+            info!("Database mode: {:?}", db_mode);
+            info!(
+                "Database cache capacity: {}",
+                Byte::from_bytes(db_cache_capacity.into()).get_appropriate_unit(true)
+            );
+            let db_config = sled::Config::new()
+                .path(db_path)
+                .mode(db_mode)
+                .cache_capacity(db_cache_capacity);
+            Db::open_sled(db_config)
+        }
+        DbBackend::Memory => Ok(Db::memory()),
+    };
+    let trees = match db.and_then(open_trees::<R>) {
         Ok(trees) => trees,
-        Err(_) => {
-            error!("Failed to open database.");
+        Err(err) => {
+            error!("Failed to open database: {}", err);
             exit(1);
         }
     };
@@ -130,39 +166,50 @@ pub async fn start<R: RuntimeIndexer + 'static>(
         let _ = close_trees::<R>(trees);
         exit(1);
     }
-    // Determine url of Substrate node to connect to.
-    let url = match url {
-        Some(url) => url,
-        None => R::get_default_url().to_owned(),
+    // Determine urls of Substrate nodes to connect to.
+    let urls = match urls.is_empty() {
+        true => vec![R::get_default_url().to_owned()],
+        false => urls,
     };
-    info!("Connecting to: {}", url);
-    let rpc_client = match RpcClient::from_url(&url).await {
-        Ok(rpc_client) => rpc_client,
-        Err(err) => {
-            error!("Failed to connect: {}", err);
-            let _ = close_trees::<R>(trees);
-            exit(1);
+    // Keep trying until a node can be reached. Other endpoints are connected at the next health
+    // check.
+    let mut backoff = Backoff::default();
+    let (endpoints, rpc) = loop {
+        let mut endpoints = Vec::with_capacity(urls.len());
+        let mut rpc = None;
+        for url in &urls {
+            info!("Connecting to: {}", url);
+            let client = match connect::<R>(url).await {
+                Ok(client) => Some(client),
+                // Nodes on another chain are never used.
+                Err(IndexError::WrongGenesisHash) => continue,
+                Err(err) => {
+                    error!("Failed to connect: {}", err);
+                    None
+                }
+            };
+            if rpc.is_none()
+                && let Some((_, endpoint_rpc)) = &client
+            {
+                rpc = Some(endpoint_rpc.clone());
+            }
+            endpoints.push(Endpoint::new(url.clone(), client));
         }
-    };
-    let api = match OnlineClient::<R::RuntimeConfig>::from_rpc_client(rpc_client.clone()).await {
-        Ok(api) => api,
-        Err(err) => {
-            error!("Failed to connect: {}", err);
+        if endpoints.is_empty() {
+            error!("No nodes are running the chain.");
             let _ = close_trees::<R>(trees);
             exit(1);
         }
+        if let Some(rpc) = rpc {
+            break (endpoints, rpc);
+        }
+        backoff.disconnected(time::Instant::now());
+        error!(
+            "Failed to connect to any node. Retrying in {} seconds.",
+            backoff.delay().as_secs()
+        );
+        time::sleep(backoff.delay()).await;
     };
-    let rpc = LegacyRpcMethods::<R::RuntimeConfig>::new(rpc_client);
-
-    let genesis_hash_api = api.genesis_hash().as_ref().to_vec();
-
-    if genesis_hash_api != genesis_hash_config {
-        error!("Chain has wrong genesis hash.");
-        error!("Correct hash: 0x{}", hex::encode(genesis_hash_config));
-        error!("Chain hash:   0x{}", hex::encode(genesis_hash_api));
-        let _ = close_trees::<R>(trees);
-        exit(1);
-    }
     // https://docs.rs/signal-hook/0.3.17/signal_hook/#a-complex-signal-handling-with-a-background-thread
     // Make sure double CTRL+C and similar kills.
     let term_now = Arc::new(AtomicBool::new(false));
@@ -179,20 +226,44 @@ pub async fn start<R: RuntimeIndexer + 'static>(
     let (exit_tx, exit_rx) = watch::channel(false);
     // Create the channel for the websockets threads to send subscribe messages to the head thread.
     let (sub_tx, sub_rx) = mpsc::unbounded_channel();
+    // Create a watch channel for the indexer thread to send new connections to the node.
+    let (rpc_tx, rpc_rx) = watch::channel(rpc);
     // Start indexer thread.
     let substrate_index = spawn(substrate_index::<R>(
         trees.clone(),
-        api.clone(),
-        rpc.clone(),
+        endpoints,
+        rpc_tx,
         queue_depth.into(),
         index_variant,
+        index_payload,
+        index_best,
+        retention,
+        target,
         exit_rx.clone(),
         sub_rx,
     ));
+    // Spawn HTTP task.
+    let http_task = http_port.map(|http_port| {
+        spawn(http_listen::<R>(
+            trees.clone(),
+            rpc_rx.clone(),
+            http_port,
+            exit_rx.clone(),
+        ))
+    });
+    // Spawn admin task.
+    let admin_task = admin_port.map(|admin_port| {
+        spawn(admin_listen::<R>(
+            trees.clone(),
+            admin_port,
+            exit_rx.clone(),
+            sub_tx.clone(),
+        ))
+    });
     // Spawn websockets task.
     let websockets_task = spawn(websockets_listen::<R>(
         trees.clone(),
-        rpc,
+        rpc_rx,
         port,
         exit_rx,
         sub_tx,
@@ -204,6 +275,12 @@ pub async fn start<R: RuntimeIndexer + 'static>(
     let _ = exit_tx.send(true);
     // Wait to exit.
     let _result = join!(substrate_index, websockets_task);
+    if let Some(http_task) = http_task {
+        let _ = http_task.await;
+    }
+    if let Some(admin_task) = admin_task {
+        let _ = admin_task.await;
+    }
     // Close db.
     let _ = close_trees::<R>(trees);
     exit(0);
@@ -0,0 +1,99 @@
+//! Versioning of the on-disk database layout.
+//!
+//! The schema version is stored in the root tree. Databases with an older version are upgraded in
+//! place by running each migration in turn. Databases with a newer version are refused.
+
+use crate::{shared::*, storage::StorageError};
+use tracing::info;
+
+/// Current version of the database layout
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Root tree key of the schema version
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Upgrades a database from version `i` to version `i + 1`.
+type Migration<CT> = fn(&Trees<CT>) -> Result<(), StorageError>;
+
+fn migrations<CT: IndexTrees>() -> [Migration<CT>; SCHEMA_VERSION as usize] {
+    [rebuild_count]
+}
+
+/// Reads the schema version of the database.
+///
+/// Databases created before the schema version was recorded are version 0. New databases do not
+/// have a version yet.
+pub fn get_schema_version<CT: IndexTrees>(trees: &Trees<CT>) -> Result<Option<u32>, StorageError> {
+    Ok(match trees.root.get(SCHEMA_VERSION_KEY)? {
+        Some(value) => Some(u32::from_be_bytes(value.as_slice().try_into().unwrap())),
+        None => match trees.root.contains_key("genesis_hash")? || !trees.span.is_empty() {
+            true => Some(0),
+            false => None,
+        },
+    })
+}
+
+pub fn set_schema_version<CT: IndexTrees>(
+    trees: &Trees<CT>,
+    version: u32,
+) -> Result<(), StorageError> {
+    trees
+        .root
+        .insert(SCHEMA_VERSION_KEY, version.to_be_bytes())?;
+    Ok(())
+}
+
+/// Checks the schema version of the database and upgrades it to [`SCHEMA_VERSION`] if necessary.
+pub fn migrate<CT: IndexTrees>(trees: &Trees<CT>) -> Result<(), StorageError> {
+    let mut version = match get_schema_version(trees)? {
+        Some(version) => version,
+        None => return set_schema_version(trees, SCHEMA_VERSION),
+    };
+    if version > SCHEMA_VERSION {
+        return Err(StorageError::IncompatibleSchema(version, SCHEMA_VERSION));
+    }
+    let migrations = migrations::<CT>();
+    while version < SCHEMA_VERSION {
+        info!(
+            "📦 Migrating database from schema version {} to {}.",
+            version,
+            version + 1
+        );
+        migrations[version as usize](trees)?;
+        version += 1;
+        // Record progress so an interrupted upgrade resumes from the last completed migration.
+        set_schema_version(trees, version)?;
+    }
+    trees.root.flush()?;
+    Ok(())
+}
+
+/// Rebuilds the count tree from the index trees. Version 1 introduced the count tree.
+pub fn rebuild_count<CT: IndexTrees>(trees: &Trees<CT>) -> Result<(), StorageError> {
+    trees.count.clear()?;
+    for tree in trees.index_trees() {
+        let mut prefix: Option<Vec<u8>> = None;
+        let mut event_count: u64 = 0;
+        for key in tree.iter().keys() {
+            let key = key?;
+            // The key prefix excludes the block number and event index.
+            let key_prefix = &key[..key.len() - 6];
+            if let Some(prefix) = &prefix
+                && prefix != key_prefix
+            {
+                trees
+                    .count
+                    .insert(count_db_key(tree, prefix), event_count.to_be_bytes())?;
+                event_count = 0;
+            }
+            prefix = Some(key_prefix.to_vec());
+            event_count += 1;
+        }
+        if let Some(prefix) = prefix {
+            trees
+                .count
+                .insert(count_db_key(tree, &prefix), event_count.to_be_bytes())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::{cell::Cell, collections::VecDeque, iter::Peekable};
+
+use crate::{shared::*, storage::StorageError, websockets::get_events};
+
+type EventIter<'a> = Box<dyn Iterator<Item = Event> + 'a>;
+
+/// Returns true if event `a` comes before event `b` in the requested order.
+fn precedes(order: Order, a: &Event, b: &Event) -> bool {
+    match order {
+        Order::Asc => a < b,
+        Order::Desc => a > b,
+    }
+}
+
+/// Events of a single key, retrieved from the database one page at a time.
+struct KeyEvents<'a, R: RuntimeIndexer> {
+    trees: &'a Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: &'a Key<R::ChainKey>,
+    query: EventQuery,
+    page: VecDeque<Event>,
+    done: bool,
+    /// Storage error that stopped the query
+    error: &'a Cell<Option<StorageError>>,
+}
+
+impl<R: RuntimeIndexer> Iterator for KeyEvents<'_, R> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if self.page.is_empty() && !self.done {
+            let events = match get_events::<R>(self.trees, self.key, &self.query) {
+                Ok(events) => events,
+                Err(error) => {
+                    self.error.set(Some(error));
+                    self.done = true;
+                    return None;
+                }
+            };
+            self.done = events.len() < self.query.limit();
+            // Continue the next page from the last event of this one.
+            match self.query.order {
+                Order::Asc => self.query.after = events.last().cloned(),
+                Order::Desc => self.query.before = events.last().cloned(),
+            };
+            self.page = events.into();
+        }
+        self.page.pop_front()
+    }
+}
+
+/// Events matched by any of the iterators, without duplicates.
+struct Union<'a> {
+    order: Order,
+    iters: Vec<Peekable<EventIter<'a>>>,
+}
+
+impl Iterator for Union<'_> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        let mut first: Option<Event> = None;
+        for iter in self.iters.iter_mut() {
+            if let Some(event) = iter.peek()
+                && first
+                    .as_ref()
+                    .is_none_or(|first| precedes(self.order, event, first))
+            {
+                first = Some(event.clone());
+            }
+        }
+        let first = first?;
+        for iter in self.iters.iter_mut() {
+            iter.next_if_eq(&first);
+        }
+        Some(first)
+    }
+}
+
+/// Events matched by all of the iterators.
+struct Intersection<'a> {
+    order: Order,
+    iters: Vec<Peekable<EventIter<'a>>>,
+}
+
+impl Iterator for Intersection<'_> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if self.iters.is_empty() {
+            return None;
+        }
+        loop {
+            // Find the furthest event at the head of any iterator.
+            let mut target = self.iters[0].peek()?.clone();
+            for iter in self.iters.iter_mut() {
+                let event = iter.peek()?;
+                if precedes(self.order, &target, event) {
+                    target = event.clone();
+                }
+            }
+            // Skip every iterator up to the target.
+            let mut matched = true;
+            for iter in self.iters.iter_mut() {
+                while let Some(event) = iter.peek()
+                    && precedes(self.order, event, &target)
+                {
+                    iter.next();
+                }
+                if iter.peek()? != &target {
+                    matched = false;
+                }
+            }
+            if matched {
+                for iter in self.iters.iter_mut() {
+                    iter.next();
+                }
+                return Some(target);
+            }
+        }
+    }
+}
+
+/// Streams the events matched by a key expression, merging the sorted events of each key. The
+/// stream ends early if there is a storage error, which is stored in `error`.
+pub fn query_events<'a, R: RuntimeIndexer + 'a>(
+    trees: &'a Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    expr: &'a KeyExpr<R::ChainKey>,
+    query: &EventQuery,
+    error: &'a Cell<Option<StorageError>>,
+) -> EventIter<'a> {
+    match expr {
+        KeyExpr::Key(key) => Box::new(KeyEvents::<R> {
+            trees,
+            key,
+            query: EventQuery {
+                limit: Some(EVENTS_LIMIT),
+                ..query.clone()
+            },
+            page: VecDeque::new(),
+            done: false,
+            error,
+        }),
+        KeyExpr::And(exprs) => Box::new(Intersection {
+            order: query.order,
+            iters: exprs
+                .iter()
+                .map(|expr| query_events::<R>(trees, expr, query, error).peekable())
+                .collect(),
+        }),
+        KeyExpr::Or(exprs) => Box::new(Union {
+            order: query.order,
+            iters: exprs
+                .iter()
+                .map(|expr| query_events::<R>(trees, expr, query, error).peekable())
+                .collect(),
+        }),
+    }
+}
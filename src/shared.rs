@@ -1,8 +1,18 @@
+use crate::storage::{Batch, Db, DbBatch, StorageError, Tree};
 use byteorder::BigEndian;
 use serde::{Deserialize, Serialize};
-use sled::{Db, Tree};
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
 use std::hash::Hash;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard};
+use subxt::{
+    config::{
+        substrate::{SubstrateHeader, U256},
+        Hasher, Header,
+    },
+    ext::codec::{Decode, Encode},
+};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::tungstenite;
 use zerocopy::{
@@ -15,22 +25,86 @@ use zerocopy_derive::*;
 #[derive(thiserror::Error, Debug)]
 pub enum IndexError {
     #[error("database error")]
-    Sled(#[from] sled::Error),
+    Storage(#[from] StorageError),
     #[error("connection error")]
     Subxt(#[from] subxt::Error),
     #[error("connection error")]
-    Tungstenite(#[from] tungstenite::Error),
+    Tungstenite(#[source] Box<tungstenite::Error>),
     #[error("parse error")]
     Hex(#[from] hex::FromHexError),
     #[error("parse error")]
     ParseError,
     #[error("connection error")]
     BlockNotFound(u32),
+    #[error("connection error")]
+    Disconnected,
+    #[error("chain has wrong genesis hash")]
+    WrongGenesisHash,
+    #[error("task failed")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("invalid request")]
+    InvalidRequest,
+    #[error("invalid request")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid request")]
+    Cbor(#[from] ciborium::de::Error<std::io::Error>),
+}
+
+impl From<tungstenite::Error> for IndexError {
+    fn from(error: tungstenite::Error) -> Self {
+        IndexError::Tungstenite(Box::new(error))
+    }
+}
+
+/// Stable error codes sent to clients
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    Database,
+    Connection,
+    Parse,
+    BlockNotFound,
+    InvalidRequest,
+}
+
+impl From<&IndexError> for ErrorCode {
+    fn from(error: &IndexError) -> Self {
+        match error {
+            // Tasks are only used to scan the database.
+            IndexError::Storage(_) | IndexError::Task(_) => ErrorCode::Database,
+            IndexError::Subxt(_)
+            | IndexError::Tungstenite(_)
+            | IndexError::Disconnected
+            | IndexError::WrongGenesisHash => ErrorCode::Connection,
+            IndexError::Hex(_) | IndexError::ParseError => ErrorCode::Parse,
+            IndexError::BlockNotFound(_) => ErrorCode::BlockNotFound,
+            IndexError::InvalidRequest | IndexError::Json(_) | IndexError::Cbor(_) => {
+                ErrorCode::InvalidRequest
+            }
+        }
+    }
+}
+
+/// Block header that records the hash of its parent
+pub trait ParentHash: Header {
+    fn parent_hash(&self) -> <Self::Hasher as Hasher>::Output;
+}
+
+impl<N, H> ParentHash for SubstrateHeader<N, H>
+where
+    N: Copy + Into<u64> + Into<U256> + TryFrom<U256> + Encode,
+    H: Hasher + Encode,
+    H::Output: Copy,
+    SubstrateHeader<N, H>: Encode + Decode,
+{
+    fn parent_hash(&self) -> H::Output {
+        self.parent_hash
+    }
 }
 
 /// Indexer for a specific chain
 pub trait RuntimeIndexer {
-    type RuntimeConfig: subxt::Config;
+    type RuntimeConfig: subxt::Config<Header: ParentHash>;
     type ChainKey: IndexKey
         + Serialize
         + for<'a> Deserialize<'a>
@@ -57,10 +131,74 @@ pub trait RuntimeIndexer {
 }
 
 pub trait IndexTrees {
-    fn open(db: &Db) -> Result<Self, sled::Error>
+    fn open(db: &Db) -> Result<Self, StorageError>
     where
         Self: Sized;
-    fn flush(&self) -> Result<(), sled::Error>;
+    fn flush(&self) -> Result<(), StorageError>;
+    /// Index trees that event keys are written to with [`write_event_key`].
+    fn trees(&self) -> Vec<&Tree>;
+    /// Removes events with keys not written with [`write_event_key`] from before `block_number`.
+    /// The trees returned by [`IndexTrees::trees`] are pruned by the indexer in chunks, so this
+    /// only needs overriding if the chain trees contain other keys.
+    fn prune(&self, _count: &Tree, _block_number: u32) -> Result<(), StorageError> {
+        Ok(())
+    }
+    /// Removes events with keys not written with [`write_event_key`] in blocks `start` to `end`
+    /// when they are re-indexed. Like [`IndexTrees::prune`], this only needs overriding if the
+    /// chain trees contain other keys.
+    fn remove_blocks(&self, _count: &Tree, _start: u32, _end: u32) -> Result<(), StorageError> {
+        Ok(())
+    }
+    /// Statistics of each chain tree. Override this if the chain trees contain keys not written
+    /// with [`write_event_key`].
+    fn stats(&self) -> Result<Vec<TreeStats>, StorageError> {
+        self.trees()
+            .into_iter()
+            .map(|tree| {
+                TreeStats::collect(tree, |key, _| {
+                    event_key_block_number(key).map(|block_number| (block_number, block_number))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Entry count, size and block range of a database tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeStats {
+    pub name: String,
+    pub entries: u64,
+    /// Total size of the keys and values in bytes, excluding database overhead.
+    pub size: u64,
+    pub first_block: Option<u32>,
+    pub last_block: Option<u32>,
+}
+
+impl TreeStats {
+    /// Scans a tree. `blocks` determines the first and last block covered by an entry.
+    pub fn collect(
+        tree: &Tree,
+        blocks: impl Fn(&[u8], &[u8]) -> Option<(u32, u32)>,
+    ) -> Result<Self, StorageError> {
+        let mut stats = TreeStats {
+            name: String::from_utf8_lossy(&tree.name()).into_owned(),
+            entries: 0,
+            size: 0,
+            first_block: None,
+            last_block: None,
+        };
+        for item in tree {
+            let (key, value) = item?;
+            stats.entries += 1;
+            stats.size += (key.len() + value.len()) as u64;
+            if let Some((first, last)) = blocks(&key, &value) {
+                stats.first_block = Some(stats.first_block.map_or(first, |block| block.min(first)));
+                stats.last_block = Some(stats.last_block.map_or(last, |block| block.max(last)));
+            }
+        }
+        Ok(stats)
+    }
 }
 
 /// Database trees for built-in Substrate keys
@@ -83,7 +221,7 @@ pub struct SubstrateTrees {
 }
 
 impl SubstrateTrees {
-    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+    pub fn open(db: &Db) -> Result<Self, StorageError> {
         Ok(SubstrateTrees {
             account_id: db.open_tree(b"account_id")?,
             account_index: db.open_tree(b"account_index")?,
@@ -102,7 +240,7 @@ impl SubstrateTrees {
         })
     }
 
-    pub fn flush(&self) -> Result<(), sled::Error> {
+    pub fn flush(&self) -> Result<(), StorageError> {
         self.account_id.flush()?;
         self.account_index.flush()?;
         self.bounty_index.flush()?;
@@ -119,16 +257,261 @@ impl SubstrateTrees {
         self.subscription_id.flush()?;
         Ok(())
     }
+
+    pub fn trees(&self) -> Vec<&Tree> {
+        vec![
+            &self.account_id,
+            &self.account_index,
+            &self.bounty_index,
+            &self.era_index,
+            &self.message_id,
+            &self.pool_id,
+            &self.preimage_hash,
+            &self.proposal_hash,
+            &self.proposal_index,
+            &self.ref_index,
+            &self.registrar_index,
+            &self.session_index,
+            &self.tip_hash,
+            &self.subscription_id,
+        ]
+    }
 }
 
 /// Database trees for the indexer
 #[derive(Clone)]
 pub struct Trees<CT> {
-    pub root: sled::Db,
+    pub root: Db,
     pub span: Tree,
     pub variant: Tree,
+    /// Number of events indexed for each key
+    pub count: Tree,
+    /// Spec version and SCALE encoded bytes of each event
+    pub payload: Tree,
+    /// Hash of each indexed block
+    pub block_hash: Tree,
+    /// Event keys added by each indexed block that has not been finalized
+    pub unfinalized: Tree,
+    /// Error of each batch block that could not be indexed
+    pub failed: Tree,
     pub substrate: SubstrateTrees,
     pub chain: CT,
+    /// Held while event counts are read and updated, so concurrent updates aren't lost
+    pub count_lock: Arc<Mutex<()>>,
+}
+
+impl<CT> Trees<CT> {
+    /// Locks the event counts until the guard is dropped.
+    pub fn lock_counts(&self) -> MutexGuard<'_, ()> {
+        self.count_lock.lock().unwrap()
+    }
+}
+
+impl<CT: IndexTrees> Trees<CT> {
+    /// All trees except the root tree.
+    pub fn all_trees(&self) -> Vec<&Tree> {
+        let mut trees = vec![
+            &self.span,
+            &self.count,
+            &self.payload,
+            &self.block_hash,
+            &self.unfinalized,
+            &self.failed,
+        ];
+        trees.extend(self.index_trees());
+        trees
+    }
+
+    /// All trees containing event keys, including the variant tree.
+    pub fn index_trees(&self) -> Vec<&Tree> {
+        let mut trees = vec![&self.variant];
+        trees.extend(self.substrate.trees());
+        trees.extend(self.chain.trees());
+        trees
+    }
+}
+
+/// Determines the event count database key for the key prefix of an index tree.
+pub fn count_db_key(tree: &Tree, prefix: &[u8]) -> Vec<u8> {
+    [&tree.name(), &[0][..], prefix].concat()
+}
+
+/// Database writes for a block that are committed atomically
+#[derive(Debug, Clone, Default)]
+pub struct IndexBatch {
+    /// Event keys to insert into each index tree
+    pub event_keys: Vec<(Tree, Vec<u8>)>,
+    /// Other writes to commit with the event keys
+    pub writes: DbBatch,
+}
+
+/// Adds the database key of an event in an index tree to a batch. The event count for the key is
+/// incremented when the batch is committed.
+pub fn write_event_key(tree: &Tree, batch: &mut IndexBatch, key: &[u8]) {
+    batch.event_keys.push((tree.clone(), key.to_vec()));
+}
+
+/// Commits a batch and the event counts of its new event keys atomically.
+pub fn commit_batch<CT>(trees: &Trees<CT>, batch: IndexBatch) -> Result<(), StorageError> {
+    commit_batch_with(trees, batch, |_, _| {})?;
+    Ok(())
+}
+
+/// Commits a batch and the event counts of its new event keys atomically, together with the
+/// writes `extra` adds for the event keys that have not been indexed before. Returns those keys.
+pub fn commit_batch_with<CT>(
+    trees: &Trees<CT>,
+    batch: IndexBatch,
+    extra: impl FnOnce(&[(Tree, Vec<u8>)], &mut DbBatch),
+) -> Result<Vec<(Tree, Vec<u8>)>, StorageError> {
+    // The event counts can't change between reading and committing them.
+    let _counts = trees.lock_counts();
+    let (mut writes, added) = batch_writes(&trees.count, batch)?;
+    extra(&added, &mut writes);
+    trees.root.commit(writes)?;
+    Ok(added)
+}
+
+/// Converts a batch into database writes, including the event counts of its new event keys.
+/// Also returns the event keys that have not been indexed before.
+#[allow(clippy::type_complexity)]
+fn batch_writes(
+    count: &Tree,
+    batch: IndexBatch,
+) -> Result<(DbBatch, Vec<(Tree, Vec<u8>)>), StorageError> {
+    let mut writes = batch.writes;
+    let mut added = Vec::new();
+    let mut new_keys = HashSet::new();
+    let mut increments: BTreeMap<Vec<u8>, u64> = BTreeMap::new();
+    for (tree, key) in batch.event_keys {
+        // Only count events that have not been indexed before.
+        if tree.contains_key(&key)? || !new_keys.insert((tree.name(), key.clone())) {
+            continue;
+        }
+        // The key prefix excludes the block number and event index.
+        *increments
+            .entry(count_db_key(&tree, &key[..key.len() - 6]))
+            .or_default() += 1;
+        writes.insert(&tree, &key, []);
+        added.push((tree, key));
+    }
+    for (count_key, increment) in increments {
+        let event_count = count.get(&count_key)?.map_or(0, |value| {
+            u64::from_be_bytes(value.as_slice().try_into().unwrap())
+        });
+        writes.insert(count, count_key, (event_count + increment).to_be_bytes());
+    }
+    Ok((writes, added))
+}
+
+/// Maximum number of keys scanned for each batch of removals, so pruning never builds an
+/// unbounded batch.
+pub const REMOVE_CHUNK_SIZE: usize = 10_000;
+
+/// Keys found by [`scan_keys`], and the last key scanned if the scan hasn't reached the end.
+pub type ScannedKeys = (Vec<Vec<u8>>, Option<Vec<u8>>);
+
+/// Big-endian block number starting at `start`, or `None` if `bytes` is too short.
+pub fn block_number_at(bytes: &[u8], start: usize) -> Option<u32> {
+    let bytes = bytes.get(start..start.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Block number of an event key. The block number is followed by the event index.
+pub fn event_key_block_number(key: &[u8]) -> Option<u32> {
+    block_number_at(key, key.len().checked_sub(6)?)
+}
+
+/// Scans up to [`REMOVE_CHUNK_SIZE`] keys of a tree between `start` and `end`. Returns the keys
+/// matching `filter`, and the last key scanned if the end hasn't been reached yet.
+pub fn scan_keys(
+    tree: &Tree,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+    filter: impl Fn(&[u8]) -> bool,
+) -> Result<ScannedKeys, StorageError> {
+    let mut keys = Vec::new();
+    let mut scanned = 0;
+    let mut last = None;
+    for key in tree
+        .range::<&[u8], _>((start, end))
+        .keys()
+        .take(REMOVE_CHUNK_SIZE)
+    {
+        let key = key?;
+        scanned += 1;
+        if filter(&key) {
+            keys.push(key.clone());
+        }
+        last = Some(key);
+    }
+    match scanned == REMOVE_CHUNK_SIZE {
+        true => Ok((keys, last)),
+        false => Ok((keys, None)),
+    }
+}
+
+/// Removes sorted event keys from an index tree and decrements the event counts. Returns the
+/// number of keys removed.
+pub fn remove_keys(tree: &Tree, count: &Tree, keys: Vec<Vec<u8>>) -> Result<u64, StorageError> {
+    let mut batch = Batch::default();
+    let mut removed: Vec<(Vec<u8>, u64)> = Vec::new();
+    for key in keys {
+        let prefix = &key[..key.len() - 6];
+        match removed.last_mut() {
+            Some((last_prefix, event_count)) if last_prefix == prefix => *event_count += 1,
+            _ => removed.push((prefix.to_vec(), 1)),
+        }
+        batch.remove(key);
+    }
+    tree.apply_batch(batch)?;
+    let mut total = 0;
+    for (prefix, removed_count) in removed {
+        total += removed_count;
+        count.update_and_fetch(count_db_key(tree, &prefix), |value| {
+            let event_count =
+                value.map_or(0, |value| u64::from_be_bytes(value.try_into().unwrap()));
+            match event_count.saturating_sub(removed_count) {
+                0 => None,
+                event_count => Some(event_count.to_be_bytes().to_vec()),
+            }
+        })?;
+    }
+    Ok(total)
+}
+
+/// Removes the database keys of all events in blocks matching `filter` from an index tree and
+/// decrements the event counts. Returns the number of keys removed.
+pub fn remove_event_keys(
+    tree: &Tree,
+    count: &Tree,
+    filter: impl Fn(u32) -> bool,
+) -> Result<u64, StorageError> {
+    let mut total = 0;
+    let mut after: Option<Vec<u8>> = None;
+    loop {
+        let start = match &after {
+            Some(after) => Bound::Excluded(after.as_slice()),
+            None => Bound::Unbounded,
+        };
+        let (keys, next) = scan_keys(tree, start, Bound::Unbounded, |key| {
+            event_key_block_number(key).is_some_and(&filter)
+        })?;
+        total += remove_keys(tree, count, keys)?;
+        match next {
+            Some(next) => after = Some(next),
+            None => return Ok(total),
+        }
+    }
+}
+
+/// Removes the database keys of all events from before `block_number` from an index tree and
+/// decrements the event counts.
+pub fn prune_tree(tree: &Tree, count: &Tree, block_number: u32) -> Result<(), StorageError> {
+    remove_event_keys(tree, count, |key_block_number| {
+        key_block_number < block_number
+    })?;
+    Ok(())
 }
 
 /// On-disk format for variant keys
@@ -159,8 +542,16 @@ pub struct U32Key {
     pub event_index: U16<BigEndian>,
 }
 
+/// On-disk format for event payload keys
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned, PartialEq, Debug)]
+#[repr(C)]
+pub struct EventKey {
+    pub block_number: U32<BigEndian>,
+    pub event_index: U16<BigEndian>,
+}
+
 /// Datatype to hold 32-byte keys
-#[derive(Copy, Clone, Debug, PartialEq, Hash, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Hash, Eq, PartialOrd, Ord)]
 pub struct Bytes32(pub [u8; 32]);
 
 impl AsRef<[u8; 32]> for Bytes32 {
@@ -280,9 +671,10 @@ impl SubstrateKey {
     pub fn write_db_key(
         &self,
         trees: &SubstrateTrees,
+        batch: &mut IndexBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) -> Result<(), StorageError> {
         let block_number = block_number.into();
         let event_index = event_index.into();
         match self {
@@ -292,7 +684,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.account_id.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.account_id, batch, key.as_bytes())
             }
             SubstrateKey::AccountIndex(account_index) => {
                 let key = U32Key {
@@ -300,7 +692,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.account_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.account_index, batch, key.as_bytes())
             }
             SubstrateKey::BountyIndex(bounty_index) => {
                 let key = U32Key {
@@ -308,7 +700,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.bounty_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.bounty_index, batch, key.as_bytes())
             }
             SubstrateKey::EraIndex(era_index) => {
                 let key = U32Key {
@@ -316,7 +708,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.era_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.era_index, batch, key.as_bytes())
             }
             SubstrateKey::MessageId(message_id) => {
                 let key = Bytes32Key {
@@ -324,7 +716,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.message_id.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.message_id, batch, key.as_bytes())
             }
             SubstrateKey::PoolId(pool_id) => {
                 let key = U32Key {
@@ -332,7 +724,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.pool_id.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.pool_id, batch, key.as_bytes())
             }
             SubstrateKey::PreimageHash(preimage_hash) => {
                 let key = Bytes32Key {
@@ -340,7 +732,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.preimage_hash.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.preimage_hash, batch, key.as_bytes())
             }
             SubstrateKey::ProposalHash(proposal_hash) => {
                 let key = Bytes32Key {
@@ -348,7 +740,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.proposal_hash.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.proposal_hash, batch, key.as_bytes())
             }
             SubstrateKey::ProposalIndex(proposal_index) => {
                 let key = U32Key {
@@ -356,7 +748,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.proposal_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.proposal_index, batch, key.as_bytes())
             }
             SubstrateKey::RefIndex(ref_index) => {
                 let key = U32Key {
@@ -364,7 +756,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.ref_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.ref_index, batch, key.as_bytes())
             }
             SubstrateKey::RegistrarIndex(registrar_index) => {
                 let key = U32Key {
@@ -372,7 +764,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.registrar_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.registrar_index, batch, key.as_bytes())
             }
             SubstrateKey::SessionIndex(session_index) => {
                 let key = U32Key {
@@ -380,7 +772,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.session_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.session_index, batch, key.as_bytes())
             }
             SubstrateKey::TipHash(tip_hash) => {
                 let key = Bytes32Key {
@@ -388,7 +780,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.tip_hash.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.tip_hash, batch, key.as_bytes())
             }
             SubstrateKey::SubscriptionId(subscription_id) => {
                 let key = Bytes32Key {
@@ -396,7 +788,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.subscription_id.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.subscription_id, batch, key.as_bytes())
             }
         };
         Ok(())
@@ -409,11 +801,23 @@ pub trait IndexKey {
     fn write_db_key(
         &self,
         trees: &Self::ChainTrees,
+        batch: &mut IndexBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error>;
+    ) -> Result<(), StorageError>;
 
-    fn get_key_events(&self, trees: &Self::ChainTrees) -> Vec<Event>;
+    fn get_key_events(
+        &self,
+        trees: &Self::ChainTrees,
+        query: &EventQuery,
+    ) -> Result<Vec<Event>, StorageError>;
+
+    fn count_key_events(
+        &self,
+        trees: &Self::ChainTrees,
+        count: &Tree,
+        range: &BlockRange,
+    ) -> Result<u64, IndexError>;
 }
 
 /// All the key types for the chain
@@ -429,9 +833,10 @@ impl<CK: IndexKey> Key<CK> {
     pub fn write_db_key(
         &self,
         trees: &Trees<CK::ChainTrees>,
+        batch: &mut IndexBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) -> Result<(), StorageError> {
         match self {
             Key::Variant(pallet_index, variant_index) => {
                 let key = VariantKey {
@@ -440,39 +845,107 @@ impl<CK: IndexKey> Key<CK> {
                     block_number: block_number.into(),
                     event_index: event_index.into(),
                 };
-                trees.variant.insert(key.as_bytes(), &[])?;
+                write_event_key(&trees.variant, batch, key.as_bytes());
             }
             Key::Substrate(substrate_key) => {
-                substrate_key.write_db_key(&trees.substrate, block_number, event_index)?;
+                substrate_key.write_db_key(&trees.substrate, batch, block_number, event_index)?;
             }
             Key::Chain(chain_key) => {
-                chain_key.write_db_key(&trees.chain, block_number, event_index)?;
+                chain_key.write_db_key(&trees.chain, batch, block_number, event_index)?;
             }
         };
         Ok(())
     }
 }
 
+/// Boolean expression over keys
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "value")]
+pub enum KeyExpr<CK: IndexKey> {
+    /// Events with the key
+    Key(Key<CK>),
+    /// Events matched by every expression
+    And(Vec<KeyExpr<CK>>),
+    /// Events matched by any expression
+    Or(Vec<KeyExpr<CK>>),
+}
+
+/// Identifier supplied by a client to correlate responses with requests
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    String(String),
+}
+
+/// JSON request with an optional id
+#[derive(Deserialize, Debug, Clone)]
+pub struct Request<CK: IndexKey> {
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub msg: RequestMessage<CK>,
+}
+
 /// JSON request messages
 #[derive(Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum RequestMessage<CK: IndexKey> {
     Status,
+    Target,
     SubscribeStatus,
     UnsubscribeStatus,
     Variants,
-    GetEvents { key: Key<CK> },
-    SubscribeEvents { key: Key<CK> },
-    UnsubscribeEvents { key: Key<CK> },
+    GetEvents {
+        key: Key<CK>,
+        #[serde(flatten)]
+        query: EventQuery,
+    },
+    Query {
+        expr: KeyExpr<CK>,
+        #[serde(flatten)]
+        query: EventQuery,
+    },
+    SubscribeEvents {
+        key: Key<CK>,
+        #[serde(flatten)]
+        range: BlockRange,
+    },
+    UnsubscribeEvents {
+        key: Key<CK>,
+    },
+    CountEvents {
+        key: Key<CK>,
+        #[serde(flatten)]
+        range: BlockRange,
+    },
+    GetEventPayloads {
+        events: Vec<Event>,
+    },
     SizeOnDisk,
+    TreeStats,
+    FailedBlocks,
 }
 
 /// Identifies an event by block number and event index
-#[derive(Serialize, Debug, Clone, Deserialize, PartialEq)]
+#[derive(Serialize, Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
 pub struct Event {
     pub block_number: u32,
     pub event_index: u16,
+    /// Hash of the block, if it has been recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<Bytes32>,
+}
+
+/// SCALE encoded event and the spec version of the runtime to decode it with
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventPayload {
+    pub block_number: u32,
+    pub event_index: u16,
+    pub spec_version: u32,
+    /// Hex encoded event record, including the phase and topics.
+    pub payload: String,
 }
 
 impl fmt::Display for Event {
@@ -485,6 +958,86 @@ impl fmt::Display for Event {
     }
 }
 
+/// Default number of events returned for a key
+pub const EVENTS_LIMIT: u32 = 100;
+
+/// Maximum number of events that can be requested for a key
+pub const EVENTS_LIMIT_MAX: u32 = 1000;
+
+/// Optional inclusive bounds on the block numbers of events
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockRange {
+    pub from_block: Option<u32>,
+    pub to_block: Option<u32>,
+}
+
+/// Root tree key of the range of blocks the indexer is configured to index
+pub const TARGET_RANGE_KEY: &str = "target_range";
+
+impl BlockRange {
+    /// Loads the target range of the indexer from the root tree.
+    pub fn load_target(root: &Db) -> Result<Self, StorageError> {
+        Ok(root
+            .get(TARGET_RANGE_KEY)?
+            .and_then(|value| serde_json::from_slice(&value).ok())
+            .unwrap_or_default())
+    }
+
+    /// Stores the target range of the indexer in the root tree.
+    pub fn save_target(&self, root: &Db) -> Result<(), StorageError> {
+        root.insert(TARGET_RANGE_KEY, serde_json::to_vec(self).unwrap())?;
+        Ok(())
+    }
+
+    pub fn contains(&self, block_number: u32) -> bool {
+        self.from_block
+            .is_none_or(|from_block| block_number >= from_block)
+            && self
+                .to_block
+                .is_none_or(|to_block| block_number <= to_block)
+    }
+}
+
+/// Order in which events are returned
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// Oldest first
+    Asc,
+    /// Newest first
+    #[default]
+    Desc,
+}
+
+/// Optional parameters for retrieving the events of a key
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventQuery {
+    /// Only return events older than this event.
+    pub before: Option<Event>,
+    /// Only return events newer than this event.
+    pub after: Option<Event>,
+    #[serde(default)]
+    pub order: Order,
+    /// Maximum number of events to return.
+    pub limit: Option<u32>,
+    #[serde(flatten)]
+    pub range: BlockRange,
+}
+
+impl EventQuery {
+    /// Maximum number of events to return. Defaults to [`EVENTS_LIMIT`] and is clamped to between
+    /// 1 and [`EVENTS_LIMIT_MAX`].
+    pub fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(EVENTS_LIMIT)
+            .clamp(1, EVENTS_LIMIT_MAX)
+            .try_into()
+            .unwrap()
+    }
+}
+
 /// Index and name of an event type
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct EventMeta {
@@ -509,6 +1062,14 @@ pub struct SpanDbValue {
     pub index_variant: u8,
 }
 
+/// Batch block that could not be indexed and will be retried
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedBlock {
+    pub block_number: u32,
+    pub error: String,
+}
+
 /// Start and end block number for a span of blocks
 #[derive(Serialize, Debug, Clone, PartialEq, Deserialize)]
 pub struct Span {
@@ -528,29 +1089,92 @@ impl fmt::Display for Span {
 #[serde(rename_all = "camelCase")]
 pub enum ResponseMessage<CK: IndexKey> {
     Status(Vec<Span>),
+    /// Range of blocks the indexer is configured to index
+    Target(BlockRange),
     Variants(Vec<PalletMeta>),
-    Events { key: Key<CK>, events: Vec<Event> },
+    Events {
+        key: Key<CK>,
+        events: Vec<Event>,
+        next: Option<Event>,
+    },
+    QueryEvents {
+        events: Vec<Event>,
+        next: Option<Event>,
+    },
     Subscribed,
     Unsubscribed,
+    EventCount {
+        key: Key<CK>,
+        count: u64,
+    },
+    /// Events of a block that has not been finalized
+    EventsAdded {
+        key: Key<CK>,
+        events: Vec<Event>,
+    },
+    /// Events of an unfinalized block that is no longer in the best chain
+    EventsRetracted {
+        key: Key<CK>,
+        events: Vec<Event>,
+    },
+    EventPayloads(Vec<EventPayload>),
     SizeOnDisk(u64),
-    //    Error,
+    TreeStats(Vec<TreeStats>),
+    FailedBlocks(Vec<FailedBlock>),
+    /// Blocks that will be re-indexed
+    Reindexing {
+        start: u32,
+        end: u32,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// JSON response with the id of the request it belongs to
+#[derive(Serialize, Debug, Clone)]
+pub struct Response<CK: IndexKey> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub msg: ResponseMessage<CK>,
+}
+
+impl<CK: IndexKey> From<IndexError> for ResponseMessage<CK> {
+    fn from(error: IndexError) -> Self {
+        ResponseMessage::Error {
+            code: ErrorCode::from(&error),
+            message: error.to_string(),
+        }
+    }
 }
 
-/// Subscription message sent from a WebSocket connection thread to the indexer thread
+/// Subscription message sent from a WebSocket connection thread to the indexer thread, or a
+/// re-index request from the HTTP server
+///
+/// Unsubscribing with an id only removes the subscription created with that id.
 #[derive(Debug)]
 pub enum SubscriptionMessage<CK: IndexKey> {
     SubscribeStatus {
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        id: Option<RequestId>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
     UnsubscribeStatus {
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        id: Option<RequestId>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
     SubscribeEvents {
         key: Key<CK>,
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        range: BlockRange,
+        id: Option<RequestId>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
     UnsubscribeEvents {
         key: Key<CK>,
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        id: Option<RequestId>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
+    /// Re-index blocks `start` to `end` while indexing.
+    ReindexBlocks { start: u32, end: u32 },
 }
@@ -0,0 +1,286 @@
+//! Export and import of index snapshots.
+//!
+//! A snapshot is a gzipped tar archive. `manifest.json` describes the snapshot and every tree is
+//! stored in its own entry as a sequence of length-prefixed keys and values.
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use tracing::{info, warn};
+use zerocopy::FromBytes;
+
+use crate::{
+    migration::{self, SCHEMA_VERSION},
+    shared::*,
+    storage::{Batch, KeyValue, StorageError, Tree},
+};
+
+const MANIFEST_PATH: &str = "manifest.json";
+
+/// Errors snapshot export and import can return
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("database error")]
+    Storage(#[from] StorageError),
+    #[error("invalid manifest")]
+    Json(#[from] serde_json::Error),
+    #[error("snapshot has no manifest")]
+    MissingManifest,
+    #[error("snapshot has wrong genesis hash")]
+    WrongGenesisHash,
+    #[error("snapshot has unsupported schema version {0}")]
+    IncompatibleSchema(u32),
+    #[error("database is not empty")]
+    NotEmpty,
+    #[error("snapshot is corrupt")]
+    Corrupt,
+    #[error("snapshot spans are not in order")]
+    InvalidSpans,
+}
+
+/// Description of the contents of a snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// Hex encoded genesis hash of the chain
+    pub genesis_hash: String,
+    pub schema_version: u32,
+    /// Spans of indexed blocks
+    pub spans: Vec<Span>,
+    /// Names of the trees in the snapshot
+    pub trees: Vec<String>,
+}
+
+fn tree_name(tree: &Tree) -> String {
+    String::from_utf8_lossy(&tree.name()).into_owned()
+}
+
+fn tree_path(name: &str) -> String {
+    format!("trees/{}", name)
+}
+
+fn read_span((key, value): &KeyValue) -> Span {
+    Span {
+        start: SpanDbValue::read_from(value).unwrap().start.into(),
+        end: u32::from_be_bytes(key.as_slice().try_into().unwrap()),
+    }
+}
+
+/// Spool file that is removed when dropped, even if the export fails
+struct Spool<'a>(&'a Path);
+
+impl Drop for Spool<'_> {
+    fn drop(&mut self) {
+        // A leftover spool file is harmless, so it shouldn't fail the export.
+        if let Err(err) = fs::remove_file(self.0) {
+            warn!(
+                "📦 Failed to remove spool file {}: {}",
+                self.0.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Checks a manifest before anything is imported. Spans must be non-empty, in ascending order and
+/// must not overlap.
+fn check_manifest<R: RuntimeIndexer>(manifest: &Manifest) -> Result<(), SnapshotError> {
+    if manifest.genesis_hash != hex::encode(R::get_genesis_hash().as_ref()) {
+        return Err(SnapshotError::WrongGenesisHash);
+    }
+    if manifest.schema_version > SCHEMA_VERSION {
+        return Err(SnapshotError::IncompatibleSchema(manifest.schema_version));
+    }
+    if manifest.spans.iter().any(|span| span.start > span.end)
+        || manifest
+            .spans
+            .windows(2)
+            .any(|spans| spans[0].end >= spans[1].start)
+    {
+        return Err(SnapshotError::InvalidSpans);
+    }
+    Ok(())
+}
+
+/// Number of records to import from a tree in each batch
+const BATCH_SIZE: usize = 10_000;
+
+/// Writes records as a sequence of length-prefixed keys and values. Returns the number of bytes
+/// written.
+fn write_records(
+    records: impl Iterator<Item = Result<KeyValue, StorageError>>,
+    out: &mut impl Write,
+) -> Result<u64, SnapshotError> {
+    let mut len = 0;
+    for record in records {
+        let (key, value) = record?;
+        for bytes in [key, value] {
+            out.write_all(&u32::try_from(bytes.len()).unwrap().to_be_bytes())?;
+            out.write_all(&bytes)?;
+            len += 4 + bytes.len() as u64;
+        }
+    }
+    Ok(len)
+}
+
+/// Reads a length-prefixed byte string from an entry with `remaining` bytes left. Lengths that
+/// run past the end of the entry are rejected before anything is allocated.
+fn read_bytes(
+    reader: &mut impl Read,
+    remaining: &mut u64,
+) -> Result<Option<Vec<u8>>, SnapshotError> {
+    if *remaining == 0 {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u64::from(u32::from_be_bytes(len));
+    if *remaining < 4 + len {
+        return Err(SnapshotError::Corrupt);
+    }
+    *remaining -= 4 + len;
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+fn read_tree(tree: &Tree, mut reader: impl Read, size: u64) -> Result<(), SnapshotError> {
+    let mut remaining = size;
+    let mut batch = Batch::default();
+    while let Some(key) = read_bytes(&mut reader, &mut remaining)? {
+        let value = read_bytes(&mut reader, &mut remaining)?.ok_or(SnapshotError::Corrupt)?;
+        batch.insert(key, value);
+        if batch.ops.len() == BATCH_SIZE {
+            tree.apply_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Writes a snapshot of all trees to `path`. The indexer must not be writing to the database.
+///
+/// Each tree is read once into a spool file next to `path`, so the size in each tar header
+/// always matches the records written and the manifest spans match the exported span tree.
+pub fn export_snapshot<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    path: &Path,
+) -> Result<Manifest, SnapshotError> {
+    let spool_path = path.with_extension("spool");
+    let _spool = Spool(&spool_path);
+    write_snapshot::<R>(trees, path, &spool_path)
+}
+
+fn write_snapshot<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    path: &Path,
+    spool_path: &Path,
+) -> Result<Manifest, SnapshotError> {
+    let all_trees = trees.all_trees();
+    let spool = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(spool_path)?;
+    let mut spool = BufWriter::new(spool);
+    let mut spans = Vec::new();
+    let mut sizes = Vec::new();
+    for tree in &all_trees {
+        info!("📦 Exporting tree {}", tree_name(tree));
+        let records = tree.iter().inspect(|record| {
+            if tree.name() == trees.span.name()
+                && let Ok(record) = record
+            {
+                spans.push(read_span(record));
+            }
+        });
+        sizes.push(write_records(records, &mut spool)?);
+    }
+    let mut spool = spool.into_inner().map_err(|err| err.into_error())?;
+    spool.seek(SeekFrom::Start(0))?;
+    let manifest = Manifest {
+        genesis_hash: hex::encode(R::get_genesis_hash().as_ref()),
+        schema_version: SCHEMA_VERSION,
+        spans,
+        trees: all_trees.iter().map(|tree| tree_name(tree)).collect(),
+    };
+    let encoder = GzEncoder::new(File::create(path)?, Compression::default());
+    let mut archive = tar::Builder::new(encoder);
+    let header = |size: u64| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header
+    };
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    archive.append_data(
+        &mut header(data.len().try_into().unwrap()),
+        MANIFEST_PATH,
+        data.as_slice(),
+    )?;
+    for (tree, size) in all_trees.into_iter().zip(sizes) {
+        archive.append_data(
+            &mut header(size),
+            tree_path(&tree_name(tree)),
+            (&mut spool).take(size),
+        )?;
+    }
+    archive.into_inner()?.finish()?.flush()?;
+    Ok(manifest)
+}
+
+/// Reads a snapshot from `path` into an empty database.
+pub fn import_snapshot<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    path: &Path,
+) -> Result<Manifest, SnapshotError> {
+    if trees.root.contains_key("genesis_hash")?
+        || trees.all_trees().iter().any(|tree| !tree.is_empty())
+    {
+        return Err(SnapshotError::NotEmpty);
+    }
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    let mut entries = archive.entries()?;
+    // The manifest is the first entry so it can be verified before importing anything.
+    let mut entry = entries.next().ok_or(SnapshotError::MissingManifest)??;
+    if entry.path()?.as_ref() != Path::new(MANIFEST_PATH) {
+        return Err(SnapshotError::MissingManifest);
+    }
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    let manifest: Manifest = serde_json::from_slice(&data)?;
+    check_manifest::<R>(&manifest)?;
+    let all_trees: HashMap<String, &Tree> = trees
+        .all_trees()
+        .into_iter()
+        .map(|tree| (tree_path(&tree_name(tree)), tree))
+        .collect();
+    for entry in entries {
+        let entry = entry?;
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+        match all_trees.get(&entry_path) {
+            Some(tree) => {
+                info!("📦 Importing tree {}", tree_name(tree));
+                let size = entry.size();
+                read_tree(tree, entry, size)?;
+            }
+            None => warn!("📦 Skipping unknown snapshot entry {}", entry_path),
+        }
+    }
+    trees
+        .root
+        .insert("genesis_hash", R::get_genesis_hash().as_ref())?;
+    // Upgrade snapshots from older versions.
+    migration::set_schema_version(trees, manifest.schema_version)?;
+    migration::migrate(trees)?;
+    Ok(manifest)
+}
@@ -0,0 +1,668 @@
+//! Storage backends for the index database.
+//!
+//! The indexer stores everything in ordered key-value trees. [`Tree`] and [`Db`] provide the
+//! operations it needs on top of any backend implementing [`StorageTree`] and [`StorageDb`].
+
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::{Bound, Deref, RangeBounds},
+    sync::{Arc, Mutex, RwLock},
+};
+
+/// Errors storage backends can return
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("sled error")]
+    Sled(#[from] sled::Error),
+    #[error("database schema version {0} is not supported (supported: {1})")]
+    IncompatibleSchema(u32, u32),
+}
+
+/// Key-value pair returned when iterating over a tree
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Bounds of a range of keys
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Iterator over a range of key-value pairs in key order
+pub type KeyValueIter = Box<dyn DoubleEndedIterator<Item = Result<KeyValue, StorageError>>>;
+
+/// Function that computes the new value of a key from its old value
+pub type UpdateFn<'a> = dyn FnMut(Option<&[u8]>) -> Option<Vec<u8>> + 'a;
+
+/// Storage backend to open the database with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DbBackend {
+    /// Persistent database using sled
+    #[default]
+    Sled,
+    /// Non-persistent in-memory database
+    Memory,
+}
+
+/// Insertions and removals to apply to a tree atomically
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.ops
+            .push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.ops.push((key.as_ref().to_vec(), None));
+    }
+}
+
+/// Insertions and removals to apply to several trees of a database atomically
+#[derive(Debug, Clone, Default)]
+pub struct DbBatch {
+    /// Batch for each tree, by tree name
+    pub batches: BTreeMap<Vec<u8>, Batch>,
+}
+
+impl DbBatch {
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, tree: &Tree, key: K, value: V) {
+        self.batches
+            .entry(tree.name())
+            .or_default()
+            .insert(key, value);
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K) {
+        self.batches.entry(tree.name()).or_default().remove(key);
+    }
+
+    /// Appends the operations of another batch.
+    pub fn extend(&mut self, other: DbBatch) {
+        for (name, batch) in other.batches {
+            self.batches.entry(name).or_default().ops.extend(batch.ops);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.values().all(|batch| batch.ops.is_empty())
+    }
+}
+
+/// Ordered key-value tree implemented by a storage backend
+pub trait StorageTree: Send + Sync {
+    fn name(&self) -> Vec<u8>;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Atomically replaces the value of a key with the result of `f`. Returns the new value.
+    fn update_and_fetch(
+        &self,
+        key: &[u8],
+        f: &mut UpdateFn,
+    ) -> Result<Option<Vec<u8>>, StorageError>;
+    fn range(&self, range: KeyRange) -> KeyValueIter;
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&self) -> Result<(), StorageError>;
+    fn flush(&self) -> Result<(), StorageError>;
+}
+
+/// Database of named trees implemented by a storage backend
+pub trait StorageDb: Send + Sync {
+    /// Default tree of the database
+    fn root(&self) -> Arc<dyn StorageTree>;
+    fn open_tree(&self, name: &[u8]) -> Result<Arc<dyn StorageTree>, StorageError>;
+    fn size_on_disk(&self) -> Result<u64, StorageError>;
+    /// Applies the batches of several trees atomically.
+    fn commit(&self, batch: DbBatch) -> Result<(), StorageError>;
+    fn flush(&self) -> Result<(), StorageError>;
+}
+
+/// Iterator over the key-value pairs of a tree
+pub struct Iter(KeyValueIter);
+
+impl Iter {
+    pub fn keys(self) -> impl DoubleEndedIterator<Item = Result<Vec<u8>, StorageError>> {
+        self.0.map(|item| item.map(|(key, _)| key))
+    }
+
+    pub fn values(self) -> impl DoubleEndedIterator<Item = Result<Vec<u8>, StorageError>> {
+        self.0.map(|item| item.map(|(_, value)| value))
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<KeyValue, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
+/// Handle to a tree of any storage backend
+#[derive(Clone)]
+pub struct Tree(Arc<dyn StorageTree>);
+
+impl std::fmt::Debug for Tree {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Tree({})", String::from_utf8_lossy(&self.0.name()))
+    }
+}
+
+impl Tree {
+    pub fn new(tree: Arc<dyn StorageTree>) -> Self {
+        Tree(tree)
+    }
+
+    pub fn name(&self) -> Vec<u8> {
+        self.0.name()
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.get(key.as_ref())
+    }
+
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool, StorageError> {
+        Ok(self.0.get(key.as_ref())?.is_some())
+    }
+
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.insert(key.as_ref(), value.as_ref())
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.remove(key.as_ref())
+    }
+
+    pub fn update_and_fetch<K: AsRef<[u8]>, F: FnMut(Option<&[u8]>) -> Option<Vec<u8>>>(
+        &self,
+        key: K,
+        mut f: F,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.update_and_fetch(key.as_ref(), &mut f)
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        let bound = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Iter(
+            self.0
+                .range((bound(range.start_bound()), bound(range.end_bound()))),
+        )
+    }
+
+    pub fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Iter {
+        let prefix = prefix.as_ref().to_vec();
+        // The end of the range is the next prefix of the same length, if there is one.
+        let mut end = prefix.clone();
+        let end = loop {
+            match end.pop() {
+                Some(u8::MAX) => continue,
+                Some(byte) => {
+                    end.push(byte + 1);
+                    break Bound::Excluded(end);
+                }
+                None => break Bound::Unbounded,
+            }
+        };
+        Iter(self.0.range((Bound::Included(prefix), end)))
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter(self.0.range((Bound::Unbounded, Bound::Unbounded)))
+    }
+
+    pub fn first(&self) -> Result<Option<KeyValue>, StorageError> {
+        self.iter().next().transpose()
+    }
+
+    pub fn last(&self) -> Result<Option<KeyValue>, StorageError> {
+        self.iter().next_back().transpose()
+    }
+
+    pub fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        self.0.apply_batch(batch)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&self) -> Result<(), StorageError> {
+        self.0.clear()
+    }
+
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()
+    }
+}
+
+impl IntoIterator for &Tree {
+    type Item = Result<KeyValue, StorageError>;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        self.iter()
+    }
+}
+
+/// Handle to a database of any storage backend. Dereferences to the default tree.
+#[derive(Clone)]
+pub struct Db {
+    db: Arc<dyn StorageDb>,
+    root: Tree,
+}
+
+impl std::fmt::Debug for Db {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Db")
+    }
+}
+
+impl Deref for Db {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.root
+    }
+}
+
+impl Db {
+    pub fn new(db: Arc<dyn StorageDb>) -> Self {
+        let root = Tree(db.root());
+        Db { db, root }
+    }
+
+    /// Opens a sled database.
+    pub fn open_sled(config: sled::Config) -> Result<Self, StorageError> {
+        let db = config.open()?;
+        Ok(Db::new(Arc::new(SledDb(db))))
+    }
+
+    /// Creates an empty in-memory database.
+    pub fn memory() -> Self {
+        Db::new(Arc::new(MemoryDb::default()))
+    }
+
+    pub fn open_tree<N: AsRef<[u8]>>(&self, name: N) -> Result<Tree, StorageError> {
+        Ok(Tree(self.db.open_tree(name.as_ref())?))
+    }
+
+    pub fn size_on_disk(&self) -> Result<u64, StorageError> {
+        self.db.size_on_disk()
+    }
+
+    /// Applies the batches of several trees atomically.
+    pub fn commit(&self, batch: DbBatch) -> Result<(), StorageError> {
+        match batch.is_empty() {
+            true => Ok(()),
+            false => self.db.commit(batch),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush()
+    }
+}
+
+fn sled_batch(batch: Batch) -> sled::Batch {
+    let mut sled_batch = sled::Batch::default();
+    for (key, value) in batch.ops {
+        match value {
+            Some(value) => sled_batch.insert(key, value),
+            None => sled_batch.remove(key),
+        }
+    }
+    sled_batch
+}
+
+struct SledTree(sled::Tree);
+
+impl StorageTree for SledTree {
+    fn name(&self) -> Vec<u8> {
+        self.0.name().to_vec()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.insert(key, value)?.map(|value| value.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.remove(key)?.map(|value| value.to_vec()))
+    }
+
+    fn update_and_fetch(
+        &self,
+        key: &[u8],
+        f: &mut UpdateFn,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.update_and_fetch(key, f)?.map(|value| value.to_vec()))
+    }
+
+    fn range(&self, range: KeyRange) -> KeyValueIter {
+        Box::new(self.0.range(range).map(|item| {
+            item.map(|(key, value)| (key.to_vec(), value.to_vec()))
+                .map_err(StorageError::from)
+        }))
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        Ok(self.0.apply_batch(sled_batch(batch))?)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&self) -> Result<(), StorageError> {
+        Ok(self.0.clear()?)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+struct SledDb(sled::Db);
+
+impl StorageDb for SledDb {
+    fn root(&self) -> Arc<dyn StorageTree> {
+        Arc::new(SledTree((*self.0).clone()))
+    }
+
+    fn open_tree(&self, name: &[u8]) -> Result<Arc<dyn StorageTree>, StorageError> {
+        Ok(Arc::new(SledTree(self.0.open_tree(name)?)))
+    }
+
+    fn size_on_disk(&self) -> Result<u64, StorageError> {
+        Ok(self.0.size_on_disk()?)
+    }
+
+    fn commit(&self, batch: DbBatch) -> Result<(), StorageError> {
+        let mut trees = Vec::new();
+        let mut batches = Vec::new();
+        for (name, batch) in batch.batches {
+            trees.push(self.0.open_tree(name)?);
+            batches.push(sled_batch(batch));
+        }
+        trees[..]
+            .transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(&batches) {
+                    tree.apply_batch(batch)?;
+                }
+                Ok::<_, ConflictableTransactionError>(())
+            })
+            .map_err(|error| match error {
+                TransactionError::Abort(error) | TransactionError::Storage(error) => error.into(),
+            })
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+type MemoryMap = Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>;
+
+#[derive(Default)]
+struct MemoryTree {
+    name: Vec<u8>,
+    map: MemoryMap,
+}
+
+impl MemoryTree {
+    fn new(name: &[u8]) -> Self {
+        MemoryTree {
+            name: name.to_vec(),
+            map: MemoryMap::default(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        let map = self.map.read().unwrap();
+        map.iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum::<usize>()
+            .try_into()
+            .unwrap()
+    }
+}
+
+/// Number of entries copied from a memory tree at a time while iterating
+const MEMORY_RANGE_CHUNK_SIZE: usize = 1000;
+
+/// Iterator over a range of a memory tree. Entries are copied a chunk at a time, so the lock is
+/// only held while a chunk is read and entries that are never reached are not copied.
+struct MemoryRange {
+    map: MemoryMap,
+    /// Keys that haven't been read into `front` or `back` yet, or `None` if there are none left
+    range: Option<KeyRange>,
+    front: VecDeque<KeyValue>,
+    back: VecDeque<KeyValue>,
+}
+
+impl MemoryRange {
+    fn read_chunk(&mut self, from_back: bool) {
+        let Some(range) = self.range.take() else {
+            return;
+        };
+        // BTreeMap panics on inverted ranges, sled returns nothing.
+        let is_empty = match &range {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start >= end,
+            _ => false,
+        };
+        if is_empty {
+            return;
+        }
+        let map = self.map.read().unwrap();
+        let entries = map
+            .range::<Vec<u8>, _>(range.clone())
+            .map(|(key, value)| (key.clone(), value.clone()));
+        let chunk: Vec<KeyValue> = match from_back {
+            false => entries.take(MEMORY_RANGE_CHUNK_SIZE).collect(),
+            true => entries.rev().take(MEMORY_RANGE_CHUNK_SIZE).collect(),
+        };
+        // The rest of the range is left for the next chunk.
+        if chunk.len() == MEMORY_RANGE_CHUNK_SIZE {
+            let (start, end) = range;
+            let last = Bound::Excluded(chunk.last().unwrap().0.clone());
+            self.range = Some(match from_back {
+                false => (last, end),
+                true => (start, last),
+            });
+        }
+        match from_back {
+            false => self.front.extend(chunk),
+            true => chunk
+                .into_iter()
+                .for_each(|entry| self.back.push_front(entry)),
+        }
+    }
+}
+
+impl Iterator for MemoryRange {
+    type Item = Result<KeyValue, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front.is_empty() {
+            self.read_chunk(false);
+        }
+        self.front
+            .pop_front()
+            .or_else(|| self.back.pop_front())
+            .map(Ok)
+    }
+}
+
+impl DoubleEndedIterator for MemoryRange {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back.is_empty() {
+            self.read_chunk(true);
+        }
+        self.back
+            .pop_back()
+            .or_else(|| self.front.pop_back())
+            .map(Ok)
+    }
+}
+
+impl StorageTree for MemoryTree {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.map.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .map
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.map.write().unwrap().remove(key))
+    }
+
+    fn update_and_fetch(
+        &self,
+        key: &[u8],
+        f: &mut UpdateFn,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut map = self.map.write().unwrap();
+        let value = f(map.get(key).map(Vec::as_slice));
+        match &value {
+            Some(value) => map.insert(key.to_vec(), value.clone()),
+            None => map.remove(key),
+        };
+        Ok(value)
+    }
+
+    fn range(&self, range: KeyRange) -> KeyValueIter {
+        Box::new(MemoryRange {
+            map: self.map.clone(),
+            range: Some(range),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        })
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        let mut map = self.map.write().unwrap();
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().unwrap().len()
+    }
+
+    fn clear(&self) -> Result<(), StorageError> {
+        self.map.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryDb {
+    root: Arc<MemoryTree>,
+    trees: Mutex<HashMap<Vec<u8>, Arc<MemoryTree>>>,
+}
+
+impl StorageDb for MemoryDb {
+    fn root(&self) -> Arc<dyn StorageTree> {
+        self.root.clone()
+    }
+
+    fn open_tree(&self, name: &[u8]) -> Result<Arc<dyn StorageTree>, StorageError> {
+        let mut trees = self.trees.lock().unwrap();
+        let tree = trees
+            .entry(name.to_vec())
+            .or_insert_with(|| Arc::new(MemoryTree::new(name)));
+        Ok(tree.clone())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, StorageError> {
+        let trees = self.trees.lock().unwrap();
+        Ok(self.root.size() + trees.values().map(|tree| tree.size()).sum::<u64>())
+    }
+
+    fn commit(&self, batch: DbBatch) -> Result<(), StorageError> {
+        let trees: Vec<Arc<MemoryTree>> = {
+            let mut trees = self.trees.lock().unwrap();
+            batch
+                .batches
+                .keys()
+                .map(|name| match *name == self.root.name {
+                    true => self.root.clone(),
+                    false => trees
+                        .entry(name.clone())
+                        .or_insert_with(|| Arc::new(MemoryTree::new(name)))
+                        .clone(),
+                })
+                .collect()
+        };
+        // Lock every tree before changing any of them. Trees are locked in name order so
+        // concurrent commits cannot deadlock.
+        let mut maps: Vec<_> = trees.iter().map(|tree| tree.map.write().unwrap()).collect();
+        for (map, batch) in maps.iter_mut().zip(batch.batches.into_values()) {
+            for (key, value) in batch.ops {
+                match value {
+                    Some(value) => map.insert(key, value),
+                    None => map.remove(&key),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
@@ -1,82 +1,645 @@
 use ahash::AHashMap;
-use futures::future;
+use futures::{future, stream, StreamExt};
 use num_format::{Locale, ToFormattedString};
-use sled::Tree;
-use std::{collections::HashMap, future::Future, sync::Mutex};
-use subxt::{backend::legacy::LegacyRpcMethods, blocks::Block, metadata::Metadata, OnlineClient};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
+    ops::Bound,
+    sync::Mutex,
+};
+use subxt::{
+    backend::{legacy::LegacyRpcMethods, rpc::RpcClient},
+    blocks::Block,
+    config::Header,
+    metadata::Metadata,
+    OnlineClient,
+};
 use tokio::{
-    sync::{mpsc, watch, RwLock},
+    sync::{mpsc, watch, Notify, RwLock},
+    task::{self, JoinHandle},
     time::{self, Duration, Instant, MissedTickBehavior},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use zerocopy::{AsBytes, FromBytes};
 
-use crate::{shared::*, websockets::process_msg_status};
+use crate::{
+    shared::*,
+    storage::{Batch, DbBatch, StorageError, Tree},
+    websockets::{get_block_hash, process_msg_status},
+};
+
+/// Subscription to status updates
+struct StatusSubscriber<CK: IndexKey> {
+    id: Option<RequestId>,
+    sub_response_tx: mpsc::UnboundedSender<Response<CK>>,
+}
+
+/// Subscription to the events of a key within a range of blocks
+struct EventsSubscriber<CK: IndexKey> {
+    range: BlockRange,
+    id: Option<RequestId>,
+    sub_response_tx: mpsc::UnboundedSender<Response<CK>>,
+}
+
+/// Writes of an indexed block waiting to be committed
+pub struct BlockBatch<CK: IndexKey> {
+    pub block_number: u32,
+    pub event_count: u32,
+    pub key_count: u32,
+    batch: IndexBatch,
+    /// Events to notify subscribers about once the block has been committed
+    events: Vec<(Key<CK>, u16)>,
+}
+
+impl<CK: IndexKey> BlockBatch<CK> {
+    fn new(block_number: u32) -> Self {
+        BlockBatch {
+            block_number,
+            event_count: 0,
+            key_count: 0,
+            batch: IndexBatch::default(),
+            events: Vec::new(),
+        }
+    }
+}
+
+/// Number of times a batch block is attempted before it is added to the failed blocks.
+pub const BATCH_ATTEMPTS: u32 = 5;
+/// Delay before a failed batch block is attempted again. It doubles with each attempt.
+pub const BATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Attempt to index a batch block
+#[derive(Debug, Clone, Copy)]
+struct BatchAttempt {
+    block_number: u32,
+    /// Number of previous attempts that failed
+    failures: u32,
+    /// Whether the block is being retried from the failed blocks
+    is_failed: bool,
+}
+
+/// Delay before reconnecting to the node. It doubles while connections keep failing.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay before reconnecting to the node.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Time a connection has to stay up before the reconnection delay is reset.
+pub const HEALTHY_CONNECTION: Duration = Duration::from_secs(60);
+/// Interval between health checks of the endpoints.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Time an endpoint has to respond to a health check.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of blocks the endpoint used for following the head can fall behind the most up-to-date
+/// endpoint before switching to it.
+pub const MAX_HEAD_LAG: u32 = 3;
+
+/// Connection to a node
+pub type Client<T> = (OnlineClient<T>, LegacyRpcMethods<T>);
+
+/// Node endpoint the indexer can use. `C` is the connection to the node.
+pub struct Endpoint<C> {
+    url: String,
+    client: Option<C>,
+    /// Whether the endpoint passed its last health check
+    is_healthy: bool,
+    /// Whether the node is on another chain. The endpoint is never used again.
+    is_wrong_chain: bool,
+    /// Best block of the node at its last health check
+    best_block: u32,
+}
+
+impl<C> Endpoint<C> {
+    /// Creates an endpoint. Endpoints without a client are connected at the next health check.
+    pub fn new(url: String, client: Option<C>) -> Self {
+        Endpoint {
+            url,
+            is_healthy: client.is_some(),
+            is_wrong_chain: false,
+            client,
+            best_block: 0,
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.is_healthy && self.client.is_some()
+    }
+}
+
+/// Endpoints of the indexer and the ones being used
+pub struct Endpoints<C> {
+    endpoints: Vec<Endpoint<C>>,
+    /// Endpoint used for following the head
+    head: usize,
+    /// Next endpoint to try for batch indexing
+    next: usize,
+}
+
+impl<C: Clone> Endpoints<C> {
+    pub fn new(endpoints: Vec<Endpoint<C>>) -> Self {
+        Endpoints {
+            endpoints,
+            head: 0,
+            next: 0,
+        }
+    }
+
+    /// Connection to the endpoint used for following the head.
+    pub fn head_client(&self) -> Option<C> {
+        self.endpoints.get(self.head)?.client.clone()
+    }
+
+    /// Selects the next healthy endpoint for batch indexing. Falls back to the endpoint used for
+    /// following the head if none are healthy.
+    pub fn batch_client(&mut self) -> Option<(usize, C)> {
+        let len = self.endpoints.len();
+        let next = self.next;
+        self.next = (next + 1) % len.max(1);
+        let index = (0..len)
+            .map(|i| (next + i) % len)
+            .find(|index| self.endpoints[*index].is_available())
+            .unwrap_or(self.head);
+        Some((index, self.endpoints.get(index)?.client.clone()?))
+    }
+
+    /// Takes an endpoint out of rotation until it passes a health check.
+    pub fn endpoint_failed(&mut self, index: usize) {
+        let endpoint = &mut self.endpoints[index];
+        if endpoint.is_healthy {
+            warn!("🩺 {} is unhealthy", endpoint.url);
+            endpoint.is_healthy = false;
+        }
+    }
+
+    /// Endpoints to health check with their connections. Unhealthy endpoints are checked without
+    /// a connection so they are reconnected. Endpoints on another chain are not checked.
+    fn to_check(&self) -> Vec<(usize, String, Option<C>)> {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, endpoint)| !endpoint.is_wrong_chain)
+            .map(|(index, endpoint)| {
+                let client = endpoint.client.clone().filter(|_| endpoint.is_healthy);
+                (index, endpoint.url.clone(), client)
+            })
+            .collect()
+    }
+
+    /// Records the results of health checks. Endpoints on another chain are taken out of rotation
+    /// for good. Fails if every endpoint is on another chain.
+    pub fn checked(
+        &mut self,
+        results: impl IntoIterator<Item = (usize, Result<(C, u32), IndexError>)>,
+    ) -> Result<(), IndexError> {
+        for (index, result) in results {
+            let endpoint = &mut self.endpoints[index];
+            match result {
+                Ok((client, best_block)) => {
+                    if !endpoint.is_healthy {
+                        info!("🩺 {} is healthy", endpoint.url);
+                    }
+                    endpoint.client = Some(client);
+                    endpoint.is_healthy = true;
+                    endpoint.best_block = best_block;
+                }
+                Err(IndexError::WrongGenesisHash) => {
+                    error!("🩺 {} is on another chain", endpoint.url);
+                    endpoint.client = None;
+                    endpoint.is_healthy = false;
+                    endpoint.is_wrong_chain = true;
+                }
+                Err(error) => {
+                    if endpoint.is_healthy {
+                        warn!("🩺 {} is unhealthy: {:?}", endpoint.url, error);
+                    }
+                    endpoint.is_healthy = false;
+                }
+            }
+        }
+        match self
+            .endpoints
+            .iter()
+            .all(|endpoint| endpoint.is_wrong_chain)
+        {
+            true => Err(IndexError::WrongGenesisHash),
+            false => Ok(()),
+        }
+    }
+
+    /// Selects the most up-to-date healthy endpoint for following the head. Returns its index and
+    /// connection.
+    pub fn select_head(&mut self) -> Option<(usize, C)> {
+        let (index, endpoint) = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, endpoint)| endpoint.is_available())
+            .max_by_key(|(index, endpoint)| (endpoint.best_block, Reverse(*index)))?;
+        info!("✨ Following the head from: {}", endpoint.url);
+        self.head = index;
+        Some((index, endpoint.client.clone()?))
+    }
+
+    /// Determines if the endpoint used for following the head has become unhealthy or fallen
+    /// behind another endpoint.
+    pub fn is_head_lagging(&self) -> bool {
+        let Some(head) = self.endpoints.get(self.head) else {
+            return false;
+        };
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.is_available())
+            .map(|endpoint| endpoint.best_block)
+            .max()
+            .is_some_and(|best_block| {
+                !head.is_available() || head.best_block + MAX_HEAD_LAG < best_block
+            })
+    }
+}
+
+/// Block from a head subscription
+enum HeadBlock<T: subxt::Config> {
+    Finalized(Block<T, OnlineClient<T>>),
+    Best(Block<T, OnlineClient<T>>),
+}
+
+/// Indexed block from a head subscription
+enum HeadUpdate<CK: IndexKey> {
+    /// A block has been finalized. `block` is `None` if it was already indexed from the best chain.
+    Finalized {
+        block_number: u32,
+        block: Option<BlockBatch<CK>>,
+    },
+    /// The best chain has changed. Indexed blocks after `fork_number` are no longer in it.
+    Best {
+        fork_number: u32,
+        blocks: Vec<(Vec<u8>, BlockBatch<CK>)>,
+    },
+}
+
+/// Block of the best chain that has been indexed before being finalized
+struct UnfinalizedBlock<CK: IndexKey> {
+    hash: Vec<u8>,
+    /// Event keys added by the block
+    keys: Vec<(Tree, Vec<u8>)>,
+    events: Vec<(Key<CK>, u16)>,
+}
+
+/// Notification for events that have been finalized
+fn events_msg<CK: IndexKey>(key: Key<CK>, events: Vec<Event>) -> ResponseMessage<CK> {
+    ResponseMessage::Events {
+        key,
+        events,
+        next: None,
+    }
+}
 
 #[allow(clippy::type_complexity)]
 pub struct Indexer<R: RuntimeIndexer + ?Sized> {
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    api: Option<OnlineClient<R::RuntimeConfig>>,
-    rpc: Option<LegacyRpcMethods<R::RuntimeConfig>>,
+    /// Nodes to index from. Their connections are replaced when reconnecting.
+    endpoints: Mutex<Endpoints<Client<R::RuntimeConfig>>>,
     index_variant: bool,
+    index_payload: bool,
     metadata_map_lock: RwLock<AHashMap<u32, Metadata>>,
-    status_sub: Mutex<Vec<mpsc::UnboundedSender<ResponseMessage<R::ChainKey>>>>,
-    events_sub_map:
-        Mutex<HashMap<Key<R::ChainKey>, Vec<mpsc::UnboundedSender<ResponseMessage<R::ChainKey>>>>>,
+    status_sub: Mutex<Vec<StatusSubscriber<R::ChainKey>>>,
+    events_sub_map: Mutex<HashMap<Key<R::ChainKey>, Vec<EventsSubscriber<R::ChainKey>>>>,
+    /// Blocks that are being indexed. Blocks with the same number, such as a best block and a
+    /// retried batch block, are indexed one at a time so their writes are never mixed up.
+    pending: Mutex<HashMap<u32, BlockBatch<R::ChainKey>>>,
+    /// Notified when a block is no longer pending
+    pending_done: Notify,
+}
+
+/// Removes a block from the pending blocks when indexing it has finished or been cancelled, and
+/// wakes up indexing of other blocks with the same number.
+struct PendingGuard<'a, R: RuntimeIndexer> {
+    indexer: &'a Indexer<R>,
+    block_number: u32,
+}
+
+impl<R: RuntimeIndexer> Drop for PendingGuard<'_, R> {
+    fn drop(&mut self) {
+        self.indexer
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.block_number);
+        self.indexer.pending_done.notify_waiters();
+    }
 }
 
 impl<R: RuntimeIndexer> Indexer<R> {
-    fn new(
+    /// Creates an indexer that uses a single connection to a node.
+    pub fn new(
         trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
         api: OnlineClient<R::RuntimeConfig>,
         rpc: LegacyRpcMethods<R::RuntimeConfig>,
         index_variant: bool,
+        index_payload: bool,
+    ) -> Self {
+        let endpoint = Endpoint::new(String::new(), Some((api, rpc)));
+        Indexer::with_endpoints(trees, vec![endpoint], index_variant, index_payload)
+    }
+
+    /// Creates an indexer that spreads batch indexing across multiple nodes.
+    pub fn with_endpoints(
+        trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+        endpoints: Vec<Endpoint<Client<R::RuntimeConfig>>>,
+        index_variant: bool,
+        index_payload: bool,
     ) -> Self {
         Indexer {
             trees,
-            api: Some(api),
-            rpc: Some(rpc),
+            endpoints: Endpoints::new(endpoints).into(),
             index_variant,
+            index_payload,
             metadata_map_lock: RwLock::new(AHashMap::new()),
             status_sub: Vec::new().into(),
             events_sub_map: HashMap::new().into(),
+            pending: HashMap::new().into(),
+            pending_done: Notify::new(),
         }
     }
 
     pub fn new_test(trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>) -> Self {
         Indexer {
             trees,
-            api: None,
-            rpc: None,
+            endpoints: Endpoints::new(Vec::new()).into(),
             index_variant: true,
+            index_payload: true,
             metadata_map_lock: RwLock::new(AHashMap::new()),
             status_sub: Vec::new().into(),
             events_sub_map: HashMap::new().into(),
+            pending: HashMap::new().into(),
+            pending_done: Notify::new(),
         }
     }
 
+    /// Connection to the endpoint used for following the head.
+    fn client(&self) -> Result<Client<R::RuntimeConfig>, IndexError> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .head_client()
+            .ok_or(IndexError::Disconnected)
+    }
+
+    /// Checks the health of all endpoints concurrently. Unhealthy endpoints are reconnected.
+    pub async fn check_endpoints(&self) -> Result<(), IndexError> {
+        let endpoints = self.endpoints.lock().unwrap().to_check();
+        let results = future::join_all(endpoints.into_iter().map(
+            |(index, url, client)| async move {
+                let result = time::timeout(HEALTH_CHECK_TIMEOUT, check_endpoint::<R>(&url, client))
+                    .await
+                    .unwrap_or(Err(IndexError::Disconnected));
+                (index, result)
+            },
+        ))
+        .await;
+        self.endpoints.lock().unwrap().checked(results)
+    }
+
+    /// Indexes the next block from the head subscription. `finalized` is the last finalized block
+    /// and `unfinalized` contains the hashes of the best chain blocks indexed after it.
     async fn index_head(
         &self,
-        next: impl Future<
-            Output = Option<
-                Result<Block<R::RuntimeConfig, OnlineClient<R::RuntimeConfig>>, subxt::Error>,
-            >,
-        >,
+        next: impl Future<Output = Option<Result<HeadBlock<R::RuntimeConfig>, subxt::Error>>>,
+        finalized: u32,
+        unfinalized: HashMap<u32, Vec<u8>>,
+    ) -> Result<HeadUpdate<R::ChainKey>, IndexError> {
+        let is_indexed = |block_number: u32, hash: &<R::RuntimeConfig as subxt::Config>::Hash| {
+            unfinalized
+                .get(&block_number)
+                .is_some_and(|indexed| indexed.as_slice() == hash.as_ref())
+        };
+        match next.await.ok_or(IndexError::Disconnected)?? {
+            HeadBlock::Finalized(block) => {
+                let block_number = block.number().into().try_into().unwrap();
+                let hash = block.hash();
+                // Blocks indexed from the best chain only need to be confirmed.
+                let block = match is_indexed(block_number, &hash) {
+                    true => None,
+                    false => Some(
+                        self.prepare(block_number, Some(hash), &self.client()?)
+                            .await?,
+                    ),
+                };
+                Ok(HeadUpdate::Finalized {
+                    block_number,
+                    block,
+                })
+            }
+            HeadBlock::Best(block) => {
+                let client = self.client()?;
+                let rpc = &client.1;
+                let (fork_number, route) = best_route(
+                    finalized,
+                    (block.number().into().try_into().unwrap(), block.hash()),
+                    block.header().parent_hash(),
+                    is_indexed,
+                    |block_number, hash| async move {
+                        let header = rpc
+                            .chain_get_header(Some(hash))
+                            .await?
+                            .ok_or(IndexError::BlockNotFound(block_number))?;
+                        Ok(header.parent_hash())
+                    },
+                )
+                .await?;
+                let mut blocks = Vec::with_capacity(route.len());
+                for (block_number, hash) in route {
+                    let block = self.prepare(block_number, Some(hash), &client).await?;
+                    blocks.push((hash.as_ref().to_vec(), block));
+                }
+                Ok(HeadUpdate::Best {
+                    fork_number,
+                    blocks,
+                })
+            }
+        }
+    }
+
+    /// Indexes a block and commits it.
+    pub async fn index_block(&self, block_number: u32) -> Result<(u32, u32, u32), IndexError> {
+        self.index_block_with(block_number, &self.client()?).await
+    }
+
+    /// Indexes a block from a specific node and commits it.
+    async fn index_block_with(
+        &self,
+        block_number: u32,
+        client: &Client<R::RuntimeConfig>,
     ) -> Result<(u32, u32, u32), IndexError> {
-        let block = next.await.unwrap()?;
-        self.index_block(block.number().into().try_into().unwrap())
-            .await
+        let block = self.prepare(block_number, None, client).await?;
+        let result = (block.block_number, block.event_count, block.key_count);
+        self.commit_block(block, DbBatch::default())?;
+        Ok(result)
+    }
+
+    /// Indexes a batch block without committing it, waiting before attempts that follow a failure.
+    async fn index_batch_block(
+        &self,
+        attempt: BatchAttempt,
+    ) -> (BatchAttempt, Result<BlockBatch<R::ChainKey>, IndexError>) {
+        if attempt.failures != 0 {
+            let delay = BATCH_RETRY_DELAY * 2_u32.pow(attempt.failures.min(7) - 1);
+            time::sleep(delay).await;
+        }
+        let batch_client = self.endpoints.lock().unwrap().batch_client();
+        let Some((endpoint, client)) = batch_client else {
+            return (attempt, Err(IndexError::Disconnected));
+        };
+        // The block is committed by the indexing loop together with its span.
+        let result = self.prepare(attempt.block_number, None, &client).await;
+        // Take the endpoint out of rotation if the request failed.
+        if let Err(IndexError::Subxt(subxt::Error::Rpc(_))) = result {
+            self.endpoints.lock().unwrap().endpoint_failed(endpoint);
+        }
+        (attempt, result)
     }
 
-    async fn index_block(&self, block_number: u32) -> Result<(u32, u32, u32), IndexError> {
+    /// Indexes a block without writing it to the database.
+    pub async fn prepare_block(
+        &self,
+        block_number: u32,
+    ) -> Result<BlockBatch<R::ChainKey>, IndexError> {
+        self.prepare(block_number, None, &self.client()?).await
+    }
+
+    /// Indexes a block without writing it to the database. If `block_hash` is `None` the block of
+    /// the best chain is indexed.
+    async fn prepare(
+        &self,
+        block_number: u32,
+        block_hash: Option<<R::RuntimeConfig as subxt::Config>::Hash>,
+        client: &Client<R::RuntimeConfig>,
+    ) -> Result<BlockBatch<R::ChainKey>, IndexError> {
+        // Writes for the block are collected until it has been fully indexed.
+        let guard = loop {
+            let done = self.pending_done.notified();
+            if let Entry::Vacant(entry) = self.pending.lock().unwrap().entry(block_number) {
+                entry.insert(BlockBatch::new(block_number));
+                break PendingGuard {
+                    indexer: self,
+                    block_number,
+                };
+            }
+            done.await;
+        };
+        let result = self.process_block(block_number, block_hash, client).await;
+        let mut block = self.pending.lock().unwrap().remove(&block_number).unwrap();
+        drop(guard);
+        (block.event_count, block.key_count) = result?;
+        Ok(block)
+    }
+
+    /// Commits a block together with other writes in one atomic unit, then notifies subscribers.
+    pub fn commit_block(
+        &self,
+        block: BlockBatch<R::ChainKey>,
+        writes: DbBatch,
+    ) -> Result<(), StorageError> {
+        let mut batch = block.batch;
+        batch.writes.extend(writes);
+        commit_batch(&self.trees, batch)?;
+        let block_hash = get_block_hash(&self.trees.block_hash, block.block_number);
+        self.notify_block(block.block_number, block_hash, &block.events, events_msg);
+        Ok(())
+    }
+
+    /// Commits a block that has not been finalized and records the event keys it adds, so it can
+    /// be rolled back. Subscribers are notified with `EventsAdded`.
+    fn commit_unfinalized(
+        &self,
+        block: BlockBatch<R::ChainKey>,
+        hash: Vec<u8>,
+    ) -> Result<UnfinalizedBlock<R::ChainKey>, StorageError> {
+        let block_number = block.block_number;
+        let keys = commit_batch_with(&self.trees, block.batch, |keys, writes| {
+            writes.insert(
+                &self.trees.unfinalized,
+                block_number.to_be_bytes(),
+                encode_event_keys(keys),
+            );
+        })?;
+        let block_hash = get_block_hash(&self.trees.block_hash, block_number);
+        self.notify_block(block_number, block_hash, &block.events, |key, events| {
+            ResponseMessage::EventsAdded { key, events }
+        });
+        Ok(UnfinalizedBlock {
+            hash,
+            keys,
+            events: block.events,
+        })
+    }
+
+    /// Rolls back a block that is no longer in the best chain. Subscribers are notified with
+    /// `EventsRetracted`.
+    fn retract_block(
+        &self,
+        block_number: u32,
+        block: UnfinalizedBlock<R::ChainKey>,
+    ) -> Result<(), StorageError> {
+        let block_hash = get_block_hash(&self.trees.block_hash, block_number);
+        rollback_block(&self.trees, block_number, &block.keys)?;
+        self.notify_block(block_number, block_hash, &block.events, |key, events| {
+            ResponseMessage::EventsRetracted { key, events }
+        });
+        Ok(())
+    }
+
+    /// Notifies subscribers that the events of a block indexed from the best chain have been
+    /// finalized.
+    fn confirm_block(&self, block_number: u32, block: UnfinalizedBlock<R::ChainKey>) {
+        let block_hash = get_block_hash(&self.trees.block_hash, block_number);
+        self.notify_block(block_number, block_hash, &block.events, events_msg);
+    }
+
+    /// Adds writes to the batch of a block that is being indexed. Returns false if the block is
+    /// not being indexed.
+    fn write_pending(
+        &self,
+        block_number: u32,
+        write: impl FnOnce(&mut BlockBatch<R::ChainKey>) -> Result<(), StorageError>,
+    ) -> Result<bool, StorageError> {
+        match self.pending.lock().unwrap().get_mut(&block_number) {
+            Some(block) => write(block).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    async fn process_block(
+        &self,
+        block_number: u32,
+        block_hash: Option<<R::RuntimeConfig as subxt::Config>::Hash>,
+        (api, rpc): &Client<R::RuntimeConfig>,
+    ) -> Result<(u32, u32), IndexError> {
         let mut key_count = 0;
-        let api = self.api.as_ref().unwrap();
-        let rpc = self.rpc.as_ref().unwrap();
 
-        let block_hash = match rpc.chain_get_block_hash(Some(block_number.into())).await? {
+        let block_hash = match block_hash {
             Some(block_hash) => block_hash,
-            None => return Err(IndexError::BlockNotFound(block_number)),
+            None => match rpc.chain_get_block_hash(Some(block_number.into())).await? {
+                Some(block_hash) => block_hash,
+                None => return Err(IndexError::BlockNotFound(block_number)),
+            },
         };
+        // Record the block hash and check if it has changed since the block was last indexed.
+        self.write_pending(block_number, |block| {
+            block.batch.writes.insert(
+                &self.trees.block_hash,
+                block_number.to_be_bytes(),
+                block_hash.as_ref(),
+            );
+            Ok(())
+        })?;
+        if let Some(old_block_hash) = self.trees.block_hash.get(block_number.to_be_bytes())?
+            && old_block_hash != block_hash.as_ref()
+        {
+            warn!(
+                "Block hash of #{} has changed since it was indexed.",
+                block_number.to_formatted_string(&Locale::en)
+            );
+        }
         // Get the runtime version of the block.
         let runtime_version = rpc.state_get_runtime_version(Some(block_hash)).await?;
 
@@ -117,6 +680,14 @@ impl<R: RuntimeIndexer> Indexer<R> {
             match event {
                 Ok(event) => {
                     let event_index = i.try_into().unwrap();
+                    if self.index_payload {
+                        self.store_payload(
+                            block_number,
+                            event_index,
+                            runtime_version.spec_version,
+                            event.bytes(),
+                        )?;
+                    }
                     if self.index_variant {
                         self.index_event(
                             Key::Variant(event.pallet_index(), event.variant_index()),
@@ -135,48 +706,222 @@ impl<R: RuntimeIndexer> Indexer<R> {
             }
         }
 
-        Ok((block_number, events.len(), key_count))
+        Ok((events.len(), key_count))
     }
 
     pub fn notify_status_subscribers(&self) {
-        let msg = process_msg_status::<R>(&self.trees.span);
-        let txs = self.status_sub.lock().unwrap();
-        for tx in txs.iter() {
-            if tx.send(msg.clone()).is_ok() {}
+        let msg = process_msg_status::<R>(&self.trees);
+        let subs = self.status_sub.lock().unwrap();
+        for sub in subs.iter() {
+            let response = Response {
+                id: sub.id.clone(),
+                msg: msg.clone(),
+            };
+            if sub.sub_response_tx.send(response).is_ok() {}
         }
     }
 
     pub fn notify_subscribers(&self, search_key: Key<R::ChainKey>, event: Event) {
-        let events_sub_map = self.events_sub_map.lock().unwrap();
-        if let Some(txs) = events_sub_map.get(&search_key) {
-            let msg = ResponseMessage::Events {
-                key: search_key,
-                events: vec![event],
+        self.notify_event(search_key, event, events_msg);
+    }
+
+    /// Notifies subscribers about the events of a block with messages created by `msg`.
+    fn notify_block(
+        &self,
+        block_number: u32,
+        block_hash: Option<Bytes32>,
+        events: &[(Key<R::ChainKey>, u16)],
+        msg: impl Fn(Key<R::ChainKey>, Vec<Event>) -> ResponseMessage<R::ChainKey>,
+    ) {
+        for (key, event_index) in events {
+            let event = Event {
+                block_number,
+                event_index: *event_index,
+                block_hash,
             };
-            for tx in txs.iter() {
-                if tx.send(msg.clone()).is_ok() {}
+            self.notify_event(key.clone(), event, &msg);
+        }
+    }
+
+    fn notify_event(
+        &self,
+        search_key: Key<R::ChainKey>,
+        event: Event,
+        msg: impl Fn(Key<R::ChainKey>, Vec<Event>) -> ResponseMessage<R::ChainKey>,
+    ) {
+        let events_sub_map = self.events_sub_map.lock().unwrap();
+        if let Some(subs) = events_sub_map.get(&search_key) {
+            let block_number = event.block_number;
+            let msg = msg(search_key, vec![event]);
+            for sub in subs.iter().filter(|sub| sub.range.contains(block_number)) {
+                let response = Response {
+                    id: sub.id.clone(),
+                    msg: msg.clone(),
+                };
+                if sub.sub_response_tx.send(response).is_ok() {}
             }
         }
     }
 
+    pub fn store_payload(
+        &self,
+        block_number: u32,
+        event_index: u16,
+        spec_version: u32,
+        bytes: &[u8],
+    ) -> Result<(), StorageError> {
+        let key = EventKey {
+            block_number: block_number.into(),
+            event_index: event_index.into(),
+        };
+        let value = [&spec_version.to_be_bytes(), bytes].concat();
+        let is_pending = self.write_pending(block_number, |block| {
+            block
+                .batch
+                .writes
+                .insert(&self.trees.payload, key.as_bytes(), &value);
+            Ok(())
+        })?;
+        if !is_pending {
+            self.trees.payload.insert(key.as_bytes(), value)?;
+        }
+        Ok(())
+    }
+
     pub fn index_event(
         &self,
         key: Key<R::ChainKey>,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
-        key.write_db_key(&self.trees, block_number, event_index)?;
+    ) -> Result<(), StorageError> {
+        let is_pending = self.write_pending(block_number, |block| {
+            key.write_db_key(&self.trees, &mut block.batch, block_number, event_index)?;
+            block.events.push((key.clone(), event_index));
+            Ok(())
+        })?;
+        if is_pending {
+            return Ok(());
+        }
+        // Events of blocks that are not being indexed are committed immediately.
+        let mut batch = IndexBatch::default();
+        key.write_db_key(&self.trees, &mut batch, block_number, event_index)?;
+        commit_batch(&self.trees, batch)?;
         self.notify_subscribers(
             key,
             Event {
                 block_number,
                 event_index,
+                block_hash: get_block_hash(&self.trees.block_hash, block_number),
             },
         );
         Ok(())
     }
 }
 
+/// Encodes the tree name and database key of each event key added by an unfinalized block.
+pub fn encode_event_keys(keys: &[(Tree, Vec<u8>)]) -> Vec<u8> {
+    let mut value = Vec::new();
+    for (tree, key) in keys {
+        let name = tree.name();
+        value.push(name.len().try_into().unwrap());
+        value.extend(name);
+        value.extend(u16::try_from(key.len()).unwrap().to_be_bytes());
+        value.extend(key);
+    }
+    value
+}
+
+/// Decodes the event keys added by an unfinalized block.
+pub fn decode_event_keys(trees: &[&Tree], mut value: &[u8]) -> Option<Vec<(Tree, Vec<u8>)>> {
+    let mut keys = Vec::new();
+    while let Some((&name_len, rest)) = value.split_first() {
+        let (name, rest) = rest.split_at_checked(name_len.into())?;
+        let (key_len, rest) = rest.split_at_checked(2)?;
+        let key_len = u16::from_be_bytes(key_len.try_into().unwrap());
+        let (key, rest) = rest.split_at_checked(key_len.into())?;
+        let tree = trees.iter().find(|tree| tree.name() == name)?;
+        keys.push(((*tree).clone(), key.to_vec()));
+        value = rest;
+    }
+    Some(keys)
+}
+
+/// Removes everything indexed for an unfinalized block, given the event keys it added.
+pub fn rollback_block<CT: IndexTrees>(
+    trees: &Trees<CT>,
+    block_number: u32,
+    keys: &[(Tree, Vec<u8>)],
+) -> Result<(), StorageError> {
+    let _counts = trees.lock_counts();
+    let mut writes = DbBatch::default();
+    let mut decrements: BTreeMap<Vec<u8>, u64> = BTreeMap::new();
+    for (tree, key) in keys {
+        if tree.contains_key(key)? {
+            *decrements
+                .entry(count_db_key(tree, &key[..key.len() - 6]))
+                .or_default() += 1;
+            writes.remove(tree, key);
+        }
+    }
+    for (count_key, decrement) in decrements {
+        let event_count = trees.count.get(&count_key)?.map_or(0, |value| {
+            u64::from_be_bytes(value.as_slice().try_into().unwrap())
+        });
+        match event_count.saturating_sub(decrement) {
+            0 => writes.remove(&trees.count, count_key),
+            event_count => writes.insert(&trees.count, count_key, event_count.to_be_bytes()),
+        }
+    }
+    for key in trees.payload.scan_prefix(block_number.to_be_bytes()).keys() {
+        writes.remove(&trees.payload, key?);
+    }
+    writes.remove(&trees.block_hash, block_number.to_be_bytes());
+    writes.remove(&trees.unfinalized, block_number.to_be_bytes());
+    trees.root.commit(writes)
+}
+
+/// Walks back from a new best block with hash `hash` and parent `parent` to the last block that has
+/// been indexed, or the last finalized block. `get_parent` looks up the parent of a block. Returns
+/// the number of the block the new best chain forks from and the blocks after it in order.
+pub async fn best_route<H: Copy, F: Future<Output = Result<H, IndexError>>>(
+    finalized: u32,
+    (mut block_number, mut hash): (u32, H),
+    parent: H,
+    is_indexed: impl Fn(u32, &H) -> bool,
+    get_parent: impl Fn(u32, H) -> F,
+) -> Result<(u32, Vec<(u32, H)>), IndexError> {
+    let mut route = Vec::new();
+    while block_number > finalized && !is_indexed(block_number, &hash) {
+        let parent = match route.is_empty() {
+            true => parent,
+            false => get_parent(block_number, hash).await?,
+        };
+        route.push((block_number, hash));
+        block_number -= 1;
+        hash = parent;
+    }
+    route.reverse();
+    Ok((block_number, route))
+}
+
+/// Rolls back all blocks that were indexed before being finalized. They may have been retracted
+/// while the indexer was not running. Subscriptions don't survive a restart, so no
+/// `EventsRetracted` notifications are sent for these blocks.
+pub fn rollback_unfinalized<CT: IndexTrees>(trees: &Trees<CT>) -> Result<(), IndexError> {
+    let index_trees = trees.index_trees();
+    let blocks = trees.unfinalized.iter().collect::<Result<Vec<_>, _>>()?;
+    for (key, value) in blocks.into_iter().rev() {
+        let block_number = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+        let keys = decode_event_keys(&index_trees, &value).ok_or(IndexError::ParseError)?;
+        info!(
+            "✨ Rolling back unfinalized block #{}",
+            block_number.to_formatted_string(&Locale::en)
+        );
+        rollback_block(trees, block_number, &keys)?;
+    }
+    Ok(())
+}
+
 pub fn load_spans<R: RuntimeIndexer>(
     span_db: &Tree,
     index_variant: bool,
@@ -185,7 +930,7 @@ pub fn load_spans<R: RuntimeIndexer>(
     'span: for (key, value) in span_db.into_iter().flatten() {
         let span_value = SpanDbValue::read_from(&value).unwrap();
         let start: u32 = span_value.start.into();
-        let mut end: u32 = u32::from_be_bytes(key.as_ref().try_into().unwrap());
+        let mut end: u32 = u32::from_be_bytes(key.as_slice().try_into().unwrap());
         // Check if variants are supposed to be indexed and they were not in this span.
         if index_variant && (span_value.index_variant != 1) {
             // Delete the span.
@@ -233,11 +978,13 @@ pub fn load_spans<R: RuntimeIndexer>(
     Ok(spans)
 }
 
+/// Merges the spans the current span has reached into it. Their removal is added to `writes`.
 pub fn check_span(
     span_db: &Tree,
+    writes: &mut DbBatch,
     spans: &mut Vec<Span>,
     current_span: &mut Span,
-) -> Result<(), IndexError> {
+) {
     while let Some(span) = spans.last() {
         // Have we indexed all the blocks after the span?
         if current_span.start > span.start && current_span.start - 1 <= span.end {
@@ -250,16 +997,26 @@ pub fn check_span(
             );
             current_span.start = span.start;
             // Remove the span.
-            span_db.remove(span.end.to_be_bytes())?;
+            writes.remove(span_db, span.end.to_be_bytes());
             spans.pop();
         } else {
             break;
         }
     }
-    Ok(())
 }
 
-pub fn check_next_batch_block(spans: &[Span], next_batch_block: &mut u32) {
+/// Determines the next block to batch index, skipping spans that have already been indexed.
+/// Returns false if there are no more blocks to index within `target`.
+pub fn check_next_batch_block(
+    spans: &[Span],
+    next_batch_block: &mut u32,
+    target: &BlockRange,
+) -> bool {
+    if let Some(to_block) = target.to_block
+        && *next_batch_block > to_block
+    {
+        *next_batch_block = to_block;
+    }
     // Figure out the next block to index, skipping the next span if we have reached it.
     let mut i = spans.len();
     while i != 0 {
@@ -268,6 +1025,249 @@ pub fn check_next_batch_block(spans: &[Span], next_batch_block: &mut u32) {
             *next_batch_block = spans[i].start - 1;
         }
     }
+    target
+        .from_block
+        .is_none_or(|from_block| *next_batch_block >= from_block)
+}
+
+/// Removes spans that end before `cutoff` and truncates spans that start before it.
+pub fn prune_spans(span_db: &Tree, spans: &mut Vec<Span>, cutoff: u32) -> Result<(), IndexError> {
+    for (key, value) in span_db.into_iter().flatten() {
+        let mut span_value = SpanDbValue::read_from(&value).unwrap();
+        let end = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+        if end < cutoff {
+            span_db.remove(key)?;
+        } else if u32::from(span_value.start) < cutoff {
+            span_value.start = cutoff.into();
+            span_db.insert(key, span_value.as_bytes())?;
+        }
+    }
+    spans.retain(|span| span.end >= cutoff);
+    for span in spans.iter_mut() {
+        span.start = span.start.max(cutoff);
+    }
+    Ok(())
+}
+
+/// Trees with keys removed when blocks are pruned or re-indexed. The flag is set for index trees,
+/// whose event counts are decremented as their keys are removed. The other trees are keyed by
+/// block number.
+fn removal_list<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+) -> Vec<(Tree, bool)> {
+    let mut list = vec![(trees.variant.clone(), true)];
+    for tree in trees.substrate.trees() {
+        list.push((tree.clone(), true));
+    }
+    for tree in trees.chain.trees() {
+        list.push((tree.clone(), true));
+    }
+    for tree in [&trees.payload, &trees.block_hash] {
+        list.push((tree.clone(), false));
+    }
+    list
+}
+
+/// Scans a chunk of keys of blocks `start` to `end` in a tree from [`removal_list`].
+fn scan_block_keys(
+    tree: &Tree,
+    is_index: bool,
+    after: Option<&[u8]>,
+    start: u32,
+    end: u32,
+) -> Result<ScannedKeys, StorageError> {
+    let start_key = start.to_be_bytes();
+    let start_bound = match after {
+        Some(after) => Bound::Excluded(after),
+        None if is_index => Bound::Unbounded,
+        None => Bound::Included(start_key.as_slice()),
+    };
+    // Keys of the other trees start with the block number.
+    let end_key = end.checked_add(1).map(u32::to_be_bytes);
+    let end_bound = match &end_key {
+        Some(end_key) if !is_index => Bound::Excluded(end_key.as_slice()),
+        _ => Bound::Unbounded,
+    };
+    scan_keys(tree, start_bound, end_bound, |key| {
+        !is_index
+            || event_key_block_number(key)
+                .is_some_and(|block_number| (start..=end).contains(&block_number))
+    })
+}
+
+/// Removes a chunk of keys scanned with [`scan_block_keys`].
+fn remove_block_keys(
+    tree: &Tree,
+    is_index: bool,
+    count: &Tree,
+    keys: Vec<Vec<u8>>,
+) -> Result<(), StorageError> {
+    match is_index {
+        true => {
+            remove_keys(tree, count, keys)?;
+        }
+        false => {
+            let mut batch = Batch::default();
+            for key in keys {
+                batch.remove(key);
+            }
+            tree.apply_batch(batch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes everything indexed for blocks `start` to `end` from the trees, one chunk at a time.
+fn remove_blocks<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    list: Vec<(Tree, bool)>,
+    start: u32,
+    end: u32,
+) -> Result<(), StorageError> {
+    for (tree, is_index) in list {
+        let mut after = None;
+        loop {
+            let (keys, next) = scan_block_keys(&tree, is_index, after.as_deref(), start, end)?;
+            let counts = trees.lock_counts();
+            remove_block_keys(&tree, is_index, &trees.count, keys)?;
+            drop(counts);
+            match next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes everything indexed for blocks before `cutoff` from all trees.
+pub fn prune_trees<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    spans: &mut Vec<Span>,
+    cutoff: u32,
+) -> Result<(), IndexError> {
+    info!(
+        "🗑️  Pruning blocks before #{}",
+        cutoff.to_formatted_string(&Locale::en)
+    );
+    prune_spans(&trees.span, spans, cutoff)?;
+    if let Some(end) = cutoff.checked_sub(1) {
+        let mut list = removal_list::<R>(trees);
+        list.push((trees.failed.clone(), false));
+        remove_blocks::<R>(trees, list, 0, end)?;
+    }
+    let _counts = trees.lock_counts();
+    trees.chain.prune(&trees.count, cutoff)?;
+    Ok(())
+}
+
+/// Failed block entry of a block waiting to be re-indexed
+const REINDEXING: &str = "Re-indexing";
+/// Maximum number of blocks a running indexer re-indexes for a single request
+pub const REINDEX_MAX_BLOCKS: u32 = 100_000;
+
+/// Deletes everything indexed for blocks `start` to `end` from all trees and records them as
+/// failed blocks, so they are indexed again when the indexer retries failed blocks. The spans are
+/// not changed.
+pub fn reindex_blocks<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    start: u32,
+    end: u32,
+) -> Result<(), IndexError> {
+    info!(
+        "🔁 Re-indexing blocks from #{} to #{}",
+        start.to_formatted_string(&Locale::en),
+        end.to_formatted_string(&Locale::en)
+    );
+    mark_reindexing(trees, start, end)?;
+    remove_blocks::<R>(trees, removal_list::<R>(trees), start, end)?;
+    let _counts = trees.lock_counts();
+    trees.chain.remove_blocks(&trees.count, start, end)?;
+    Ok(())
+}
+
+/// Records blocks `start` to `end` as failed before their keys are removed, so they are still
+/// re-indexed if the indexer is stopped while removing them.
+fn mark_reindexing<CT: IndexTrees>(
+    trees: &Trees<CT>,
+    start: u32,
+    end: u32,
+) -> Result<(), StorageError> {
+    let mut batch = Batch::default();
+    let mut size = 0;
+    for block_number in start..=end {
+        batch.insert(block_number.to_be_bytes(), REINDEXING);
+        size += 1;
+        if size == REMOVE_CHUNK_SIZE {
+            trees.failed.apply_batch(std::mem::take(&mut batch))?;
+            size = 0;
+        }
+    }
+    trees.failed.apply_batch(batch)
+}
+
+/// Removal of the keys of blocks `start` to `end` while indexing, when pruning or re-indexing.
+/// Keys are scanned in chunks on the blocking thread pool and removed by the indexing loop, so
+/// event counts aren't updated concurrently. Removals run one at a time so the same key is never
+/// removed twice.
+struct Removal {
+    start: u32,
+    end: u32,
+    is_reindex: bool,
+    /// Trees still to be cleared. The first one is being scanned.
+    trees: Vec<(Tree, bool)>,
+    task: Option<JoinHandle<Result<ScannedKeys, StorageError>>>,
+}
+
+impl Removal {
+    fn new(trees: Vec<(Tree, bool)>, start: u32, end: u32, is_reindex: bool) -> Removal {
+        Removal {
+            start,
+            end,
+            is_reindex,
+            trees,
+            task: None,
+        }
+    }
+
+    /// Starts scanning the next chunk of the first tree after `after`.
+    fn scan(&mut self, after: Option<Vec<u8>>) {
+        let (tree, is_index) = self.trees[0].clone();
+        let (start, end) = (self.start, self.end);
+        self.task = Some(task::spawn_blocking(move || {
+            scan_block_keys(&tree, is_index, after.as_deref(), start, end)
+        }));
+    }
+
+    /// Removes a scanned chunk and starts scanning the next one. Returns `false` when all the
+    /// trees have been cleared.
+    fn remove_chunk(
+        &mut self,
+        count: &Tree,
+        keys: Vec<Vec<u8>>,
+        next: Option<Vec<u8>>,
+    ) -> Result<bool, StorageError> {
+        let (tree, is_index) = &self.trees[0];
+        remove_block_keys(tree, *is_index, count, keys)?;
+        if next.is_none() {
+            self.trees.remove(0);
+        }
+        match self.trees.is_empty() {
+            true => Ok(false),
+            false => {
+                self.scan(next);
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Queues a removal, starting it if no other removal is running.
+fn push_removal(removals: &mut VecDeque<Removal>, removal: Removal) {
+    removals.push_back(removal);
+    if removals.len() == 1 {
+        removals[0].scan(None);
+    }
 }
 
 pub fn process_sub_msg<R: RuntimeIndexer>(
@@ -275,47 +1275,151 @@ pub fn process_sub_msg<R: RuntimeIndexer>(
     msg: SubscriptionMessage<R::ChainKey>,
 ) {
     match msg {
-        SubscriptionMessage::SubscribeStatus { sub_response_tx } => {
-            let mut txs = indexer.status_sub.lock().unwrap();
-            txs.push(sub_response_tx);
+        SubscriptionMessage::SubscribeStatus {
+            id,
+            sub_response_tx,
+        } => {
+            let mut subs = indexer.status_sub.lock().unwrap();
+            subs.push(StatusSubscriber {
+                id,
+                sub_response_tx,
+            });
         }
-        SubscriptionMessage::UnsubscribeStatus { sub_response_tx } => {
-            let mut txs = indexer.status_sub.lock().unwrap();
-            txs.retain(|value| !sub_response_tx.same_channel(value));
+        SubscriptionMessage::UnsubscribeStatus {
+            id,
+            sub_response_tx,
+        } => {
+            let mut subs = indexer.status_sub.lock().unwrap();
+            subs.retain(|sub| {
+                !(sub_response_tx.same_channel(&sub.sub_response_tx)
+                    && id.as_ref().is_none_or(|id| sub.id.as_ref() == Some(id)))
+            });
         }
         SubscriptionMessage::SubscribeEvents {
             key,
+            range,
+            id,
             sub_response_tx,
         } => {
             let mut events_sub_map = indexer.events_sub_map.lock().unwrap();
+            let sub = EventsSubscriber {
+                range,
+                id,
+                sub_response_tx,
+            };
             match events_sub_map.get_mut(&key) {
-                Some(txs) => {
-                    txs.push(sub_response_tx);
+                Some(subs) => {
+                    subs.push(sub);
                 }
                 None => {
-                    let txs = vec![sub_response_tx];
-                    events_sub_map.insert(key, txs);
+                    let subs = vec![sub];
+                    events_sub_map.insert(key, subs);
                 }
             };
         }
         SubscriptionMessage::UnsubscribeEvents {
             key,
+            id,
             sub_response_tx,
         } => {
             let mut events_sub_map = indexer.events_sub_map.lock().unwrap();
-            if let Some(txs) = events_sub_map.get_mut(&key) {
-                txs.retain(|value| !sub_response_tx.same_channel(value));
+            if let Some(subs) = events_sub_map.get_mut(&key) {
+                subs.retain(|sub| {
+                    !(sub_response_tx.same_channel(&sub.sub_response_tx)
+                        && id.as_ref().is_none_or(|id| sub.id.as_ref() == Some(id)))
+                });
             };
         }
+        // Re-indexing is handled by the indexing loop.
+        SubscriptionMessage::ReindexBlocks { .. } => {}
     };
 }
 
+/// Connects to a node and checks that it is running the chain of `R`.
+pub async fn connect<R: RuntimeIndexer>(url: &str) -> Result<Client<R::RuntimeConfig>, IndexError> {
+    let rpc_client = RpcClient::from_url(url).await?;
+    let api = OnlineClient::<R::RuntimeConfig>::from_rpc_client(rpc_client.clone()).await?;
+    let rpc = LegacyRpcMethods::<R::RuntimeConfig>::new(rpc_client);
+    let genesis_hash_config = R::get_genesis_hash();
+    let genesis_hash_api = api.genesis_hash();
+    if genesis_hash_api != genesis_hash_config {
+        error!("Chain has wrong genesis hash.");
+        error!("Correct hash: 0x{}", hex::encode(genesis_hash_config));
+        error!("Chain hash:   0x{}", hex::encode(genesis_hash_api));
+        return Err(IndexError::WrongGenesisHash);
+    }
+    Ok((api, rpc))
+}
+
+/// Checks that a node responds, connecting to it if there is no client. Returns the client and the
+/// best block of the node.
+async fn check_endpoint<R: RuntimeIndexer>(
+    url: &str,
+    client: Option<Client<R::RuntimeConfig>>,
+) -> Result<(Client<R::RuntimeConfig>, u32), IndexError> {
+    let client = match client {
+        Some(client) => client,
+        None => connect::<R>(url).await?,
+    };
+    let header = client
+        .1
+        .chain_get_header(None)
+        .await?
+        .ok_or(IndexError::Disconnected)?;
+    let best_block = header.number().into().try_into().unwrap();
+    Ok((client, best_block))
+}
+
+/// Returns the delay before reconnecting after a connection that stayed up for `connected`. The
+/// previous `delay` is doubled unless the connection was healthy for long enough.
+pub fn reconnect_delay(delay: Duration, connected: Duration) -> Duration {
+    match connected >= HEALTHY_CONNECTION {
+        true => RECONNECT_DELAY,
+        false => (delay * 2).clamp(RECONNECT_DELAY, MAX_RECONNECT_DELAY),
+    }
+}
+
+/// Backoff between connections to the nodes. The delay doubles while connections keep failing and
+/// is reset by a healthy connection.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    delay: Duration,
+    connected: Option<Instant>,
+}
+
+impl Backoff {
+    /// Delay before the next connection attempt.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Records that a connection was established at `now`.
+    pub fn connected(&mut self, now: Instant) {
+        self.connected = Some(now);
+    }
+
+    /// Records that the connection was lost, or could not be established, at `now`.
+    pub fn disconnected(&mut self, now: Instant) {
+        let connected = self.connected.take().map_or(Duration::ZERO, |connected| {
+            now.saturating_duration_since(connected)
+        });
+        self.delay = reconnect_delay(self.delay, connected);
+    }
+}
+
+/// Indexes the chain of `R` from the nodes of `endpoints`, reconnecting with backoff when the
+/// connections are lost. The connection used for following the head is sent on `rpc_tx`.
+#[allow(clippy::too_many_arguments)]
 pub async fn substrate_index<R: RuntimeIndexer>(
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    api: OnlineClient<R::RuntimeConfig>,
-    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    endpoints: Vec<Endpoint<Client<R::RuntimeConfig>>>,
+    rpc_tx: watch::Sender<LegacyRpcMethods<R::RuntimeConfig>>,
     queue_depth: u32,
     index_variant: bool,
+    index_payload: bool,
+    index_best: bool,
+    retention: Option<u32>,
+    target: BlockRange,
     mut exit_rx: watch::Receiver<bool>,
     mut sub_rx: mpsc::UnboundedReceiver<SubscriptionMessage<R::ChainKey>>,
 ) -> Result<(), IndexError> {
@@ -326,23 +1430,168 @@ pub async fn substrate_index<R: RuntimeIndexer>(
             true => "enabled",
         },
     );
+    info!(
+        "📇 Event payload storage: {}",
+        match index_payload {
+            false => "disabled",
+            true => "enabled",
+        },
+    );
+    info!(
+        "📇 Best block indexing: {}",
+        match index_best {
+            false => "disabled",
+            true => "enabled",
+        },
+    );
+    if let Some(retention) = retention {
+        info!(
+            "📇 Retention: last {} blocks",
+            retention.to_formatted_string(&Locale::en)
+        );
+    }
+    if target != BlockRange::default() {
+        info!(
+            "📇 Target range: {} to {}",
+            target
+                .from_block
+                .map_or("genesis".to_owned(), |from_block| format!(
+                    "#{}",
+                    from_block.to_formatted_string(&Locale::en)
+                )),
+            target
+                .to_block
+                .map_or("head".to_owned(), |to_block| format!(
+                    "#{}",
+                    to_block.to_formatted_string(&Locale::en)
+                )),
+        );
+    }
+    target.save_target(&trees.root)?;
+    info!("📇 Endpoints: {}", endpoints.len());
+    let indexer = Indexer::<R>::with_endpoints(trees, endpoints, index_variant, index_payload);
+    let mut backoff = Backoff::default();
+    let mut reconnect = false;
+    loop {
+        if reconnect {
+            info!("Reconnecting in {} seconds.", backoff.delay().as_secs());
+            tokio::select! {
+                biased;
+
+                _ = exit_rx.changed() => return Ok(()),
+                _ = time::sleep(backoff.delay()) => {}
+            }
+        }
+        // Follow the head from the most up-to-date endpoint.
+        indexer.check_endpoints().await?;
+        let head = indexer.endpoints.lock().unwrap().select_head();
+        let Some((_, (_, rpc))) = head else {
+            error!("No healthy endpoints.");
+            backoff.disconnected(Instant::now());
+            reconnect = true;
+            continue;
+        };
+        let _ = rpc_tx.send(rpc);
+        backoff.connected(Instant::now());
+        let result = index_chain(
+            &indexer,
+            queue_depth,
+            index_variant,
+            index_best,
+            retention,
+            &target,
+            &mut exit_rx,
+            &mut sub_rx,
+        )
+        .await;
+        // The delay is carried across connections that fail quickly.
+        backoff.disconnected(Instant::now());
+        reconnect = match result {
+            Ok(()) if *exit_rx.borrow() => return Ok(()),
+            // Another endpoint is more up-to-date.
+            Ok(()) => false,
+            Err(error @ (IndexError::Subxt(_) | IndexError::Disconnected)) => {
+                error!("Lost connection to the node: {:?}", error);
+                true
+            }
+            Err(error) => return Err(error),
+        };
+    }
+}
+
+/// Unwraps `$result`, or breaks out of the loop labelled `$label` with the error.
+macro_rules! try_or_break {
+    ($label:lifetime, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => break $label Err(error.into()),
+        }
+    };
+}
+
+/// Indexes the chain until exiting, the connection to the node is lost or the head should be
+/// followed from another endpoint. Head and batch indexing resume from the recorded spans.
+#[allow(clippy::too_many_arguments)]
+async fn index_chain<R: RuntimeIndexer>(
+    indexer: &Indexer<R>,
+    queue_depth: u32,
+    index_variant: bool,
+    index_best: bool,
+    retention: Option<u32>,
+    target: &BlockRange,
+    exit_rx: &mut watch::Receiver<bool>,
+    sub_rx: &mut mpsc::UnboundedReceiver<SubscriptionMessage<R::ChainKey>>,
+) -> Result<(), IndexError> {
+    let trees = indexer.trees.clone();
+    let (api, _) = indexer.client()?;
     // Subscribe to all finalized blocks:
     let mut blocks_sub = api.blocks().subscribe_finalized().await?;
     // Determine the correct block to start batch indexing.
     let mut next_batch_block: u32 = blocks_sub
         .next()
         .await
-        .ok_or(IndexError::BlockNotFound(0))??
+        .ok_or(IndexError::Disconnected)??
         .number()
         .into()
         .try_into()
         .unwrap();
+    // Head blocks are only indexed until the end of the target range.
+    let mut is_following = true;
+    if let Some(to_block) = target.to_block
+        && next_batch_block >= to_block
+    {
+        next_batch_block = to_block;
+        is_following = false;
+    }
     info!(
         "📚 Indexing backwards from #{}",
         next_batch_block.to_formatted_string(&Locale::en)
     );
+    // Follow finalized blocks and, if enabled, the best chain.
+    let finalized_sub = blocks_sub.map(|block| block.map(HeadBlock::Finalized));
+    let mut head_sub = match index_best {
+        false => finalized_sub.boxed(),
+        true => {
+            let best_sub = api.blocks().subscribe_best().await?;
+            stream::select(
+                finalized_sub,
+                best_sub.map(|block| block.map(HeadBlock::Best)),
+            )
+            .boxed()
+        }
+    };
+    // Blocks indexed from the best chain before the indexer was stopped may have been retracted.
+    rollback_unfinalized(&trees)?;
     // Load already indexed spans from the db.
     let mut spans = load_spans::<R>(&trees.span, index_variant)?;
+    // Oldest block to keep according to the retention policy.
+    let retention_cutoff =
+        |head: u32| retention.map_or(0, |retention| (head + 1).saturating_sub(retention));
+    let mut head_block = next_batch_block;
+    let mut cutoff = retention_cutoff(head_block);
+    if cutoff != 0 {
+        prune_trees::<R>(&trees, &mut spans, cutoff)?;
+    }
     // If the first head block to be indexed will be touching the last span (the indexer was restarted), set the current span to the last span. Otherwise there will be no batch block indexed to connect the current span to the last span.
     let mut current_span = if let Some(span) = spans.last()
         && span.end == next_batch_block
@@ -361,22 +1610,39 @@ pub async fn substrate_index<R: RuntimeIndexer>(
         next_batch_block = span.start - 1;
         span
     } else {
+        // The span is empty until a block has been indexed.
         Span {
             start: next_batch_block + 1,
-            end: next_batch_block + 1,
+            end: next_batch_block,
         }
     };
 
-    let indexer = Indexer::<R>::new(trees.clone(), api, rpc, index_variant);
+    // Blocks of the best chain that have been indexed but not finalized.
+    let mut unfinalized: BTreeMap<u32, UnfinalizedBlock<R::ChainKey>> = BTreeMap::new();
+    let unfinalized_hashes = |unfinalized: &BTreeMap<u32, UnfinalizedBlock<R::ChainKey>>| {
+        unfinalized
+            .iter()
+            .map(|(block_number, block)| (*block_number, block.hash.clone()))
+            .collect()
+    };
 
-    let mut head_future = Box::pin(indexer.index_head(blocks_sub.next()));
+    let mut head_future = Box::pin(indexer.index_head(head_sub.next(), head_block, HashMap::new()));
 
     info!("📚 Queue depth: {}", queue_depth);
     let mut futures = Vec::with_capacity(queue_depth.try_into().unwrap());
 
     for _ in 0..queue_depth {
-        check_next_batch_block(&spans, &mut next_batch_block);
-        futures.push(Box::pin(indexer.index_block(next_batch_block)));
+        // Don't index blocks outside the target range or the retention window.
+        if !check_next_batch_block(&spans, &mut next_batch_block, target)
+            || next_batch_block < cutoff
+        {
+            break;
+        }
+        futures.push(Box::pin(indexer.index_batch_block(BatchAttempt {
+            block_number: next_batch_block,
+            failures: 0,
+            is_failed: false,
+        })));
         debug!(
             "⬆️  Block #{} queued.",
             next_batch_block.to_formatted_string(&Locale::en)
@@ -395,61 +1661,195 @@ pub async fn substrate_index<R: RuntimeIndexer>(
     let mut interval = time::interval_at(Instant::now() + interval_duration, interval_duration);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    let mut is_batching = true;
+    let mut is_batching = !futures.is_empty();
 
-    loop {
+    let prune_duration = Duration::from_secs(600);
+    let mut prune_interval = time::interval_at(Instant::now() + prune_duration, prune_duration);
+    prune_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // Keys of pruned and re-indexed blocks are removed in the background.
+    let mut removals: VecDeque<Removal> = VecDeque::new();
+
+    // Failed blocks are retried periodically. At most `queue_depth` are retried at a time.
+    let mut retrying: HashSet<u32> = HashSet::new();
+    let mut retry_queue: VecDeque<u32> = VecDeque::new();
+    // Failed blocks from this block onwards still have to be queued.
+    let mut retry_from: Option<u32> = None;
+    let retry_duration = Duration::from_secs(600);
+    let mut retry_interval = time::interval_at(Instant::now() + retry_duration, retry_duration);
+    retry_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    // Endpoints are checked without pausing indexing.
+    let health_check = || async {
+        time::sleep(HEALTH_CHECK_INTERVAL).await;
+        indexer.check_endpoints().await
+    };
+    let mut health_future = Box::pin(health_check());
+
+    let result = 'index: loop {
         tokio::select! {
             biased;
 
-            _ = exit_rx.changed() => {
-                if current_span.start != current_span.end {
-                    let value = SpanDbValue {
-                        start: current_span.start.into(),
-                        version: (R::get_versions().len() - 1).try_into().unwrap(),
-                        index_variant: index_variant.into(),
-                    };
-                    trees.span.insert(current_span.end.to_be_bytes(), value.as_bytes())?;
-                    info!(
-                        "📚 Recording current indexed span from #{} to #{}",
-                        current_span.start.to_formatted_string(&Locale::en),
-                        current_span.end.to_formatted_string(&Locale::en)
-                    );
-                }
-                return Ok(());
-            }
-            Some(msg) = sub_rx.recv() => process_sub_msg(&indexer, msg),
-            result = &mut head_future => {
+            _ = exit_rx.changed() => break Ok(()),
+            Some(msg) = sub_rx.recv() => match msg {
+                SubscriptionMessage::ReindexBlocks { start, end } => {
+                    // Unfinalized blocks are indexed from the head and pruned blocks aren't kept.
+                    let start = start.max(cutoff);
+                    let end = end.min(head_block);
+                    if start <= end {
+                        info!(
+                            "🔁 Re-indexing blocks from #{} to #{}",
+                            start.to_formatted_string(&Locale::en),
+                            end.to_formatted_string(&Locale::en)
+                        );
+                        try_or_break!('index, mark_reindexing(&trees, start, end));
+                        push_removal(&mut removals, Removal::new(removal_list::<R>(&trees), start, end, true));
+                    }
+                },
+                msg => process_sub_msg(indexer, msg),
+            },
+            result = &mut head_future, if is_following => {
                 match result {
-                    Ok((block_number, event_count, key_count)) => {
-                        trees.span.remove(current_span.end.to_be_bytes())?;
-                        current_span.end = block_number;
+                    Ok(HeadUpdate::Finalized { block_number, .. }) if target.to_block.is_some_and(|to_block| block_number > to_block) => {
+                        info!("✨ Reached the end of the target range.");
+                        is_following = false;
+                    },
+                    Ok(HeadUpdate::Finalized { block_number, block }) => {
+                        // Commit the block together with the span that includes it.
+                        let mut writes = DbBatch::default();
+                        writes.remove(&trees.span, current_span.end.to_be_bytes());
                         let value = SpanDbValue {
                             start: current_span.start.into(),
                             version: (R::get_versions().len() - 1).try_into().unwrap(),
                             index_variant: index_variant.into(),
                         };
-                        trees.span.insert(current_span.end.to_be_bytes(), value.as_bytes())?;
-                        info!(
-                            "✨ #{}: {} events, {} keys",
-                            block_number.to_formatted_string(&Locale::en),
-                            event_count.to_formatted_string(&Locale::en),
-                            key_count.to_formatted_string(&Locale::en),
-                        );
+                        writes.insert(&trees.span, block_number.to_be_bytes(), value.as_bytes());
+                        // A different block has been finalized, so the indexed blocks from this height are on a dead fork.
+                        if block.is_some() {
+                            while let Some((&unfinalized_number, _)) = unfinalized.last_key_value()
+                                && unfinalized_number >= block_number
+                            {
+                                let retracted = unfinalized.remove(&unfinalized_number).unwrap();
+                                try_or_break!('index, indexer.retract_block(unfinalized_number, retracted));
+                                info!("✨ #{} retracted", unfinalized_number.to_formatted_string(&Locale::en));
+                            }
+                        }
+                        let remaining = unfinalized.split_off(&(block_number + 1));
+                        let confirmed = std::mem::replace(&mut unfinalized, remaining);
+                        for unfinalized_number in confirmed.keys() {
+                            writes.remove(&trees.unfinalized, unfinalized_number.to_be_bytes());
+                        }
+                        match block {
+                            Some(block) => {
+                                let (event_count, key_count) = (block.event_count, block.key_count);
+                                try_or_break!('index, indexer.commit_block(block, writes));
+                                info!(
+                                    "✨ #{}: {} events, {} keys",
+                                    block_number.to_formatted_string(&Locale::en),
+                                    event_count.to_formatted_string(&Locale::en),
+                                    key_count.to_formatted_string(&Locale::en),
+                                );
+                            },
+                            None => {
+                                try_or_break!('index, trees.root.commit(writes));
+                                info!("✨ #{} finalized", block_number.to_formatted_string(&Locale::en));
+                            },
+                        }
+                        // The span only includes the block once it has been committed.
+                        current_span.end = block_number;
+                        for (confirmed_number, confirmed_block) in confirmed {
+                            indexer.confirm_block(confirmed_number, confirmed_block);
+                        }
+                        head_block = block_number;
                         indexer.notify_status_subscribers();
-                        drop(head_future);
-                        head_future = Box::pin(indexer.index_head(blocks_sub.next()));
+                    },
+                    Ok(HeadUpdate::Best { fork_number, blocks }) => {
+                        // Retract indexed blocks that are no longer in the best chain.
+                        while let Some((&unfinalized_number, _)) = unfinalized.last_key_value()
+                            && unfinalized_number > fork_number
+                        {
+                            let retracted = unfinalized.remove(&unfinalized_number).unwrap();
+                            try_or_break!('index, indexer.retract_block(unfinalized_number, retracted));
+                            info!("✨ #{} retracted", unfinalized_number.to_formatted_string(&Locale::en));
+                        }
+                        for (hash, block) in blocks {
+                            let (block_number, event_count, key_count) = (block.block_number, block.event_count, block.key_count);
+                            // The block has been finalized while it was being indexed or is outside the target range.
+                            if block_number <= head_block || !target.contains(block_number) {
+                                continue;
+                            }
+                            let block = try_or_break!('index, indexer.commit_unfinalized(block, hash));
+                            unfinalized.insert(block_number, block);
+                            info!(
+                                "✨ Best #{}: {} events, {} keys",
+                                block_number.to_formatted_string(&Locale::en),
+                                event_count.to_formatted_string(&Locale::en),
+                                key_count.to_formatted_string(&Locale::en),
+                            );
+                        }
                     },
                     Err(error) => {
                         match error {
                             IndexError::BlockNotFound(block_number) => {
                                 error!("✨ Block not found #{}", block_number.to_formatted_string(&Locale::en));
                             },
+                            // Reconnect if the head subscription fails.
+                            error @ (IndexError::Subxt(_) | IndexError::Disconnected) => break Err(error),
                             err => {
                                 error!("✨ Indexing failed: {}", err);
                             },
                         }
                     },
                 };
+                drop(head_future);
+                head_future = Box::pin(indexer.index_head(head_sub.next(), head_block, unfinalized_hashes(&unfinalized)));
+            }
+            result = &mut health_future => {
+                try_or_break!('index, result);
+                if indexer.endpoints.lock().unwrap().is_head_lagging() {
+                    info!("✨ Switching to a more up-to-date endpoint.");
+                    break Ok(());
+                }
+                health_future = Box::pin(health_check());
+            }
+            _ = prune_interval.tick(), if retention.is_some() && removals.iter().all(|removal| removal.is_reindex) => {
+                let new_cutoff = retention_cutoff(head_block);
+                if new_cutoff > cutoff {
+                    cutoff = new_cutoff;
+                    info!(
+                        "🗑️  Pruning blocks before #{}",
+                        cutoff.to_formatted_string(&Locale::en)
+                    );
+                    try_or_break!('index, prune_spans(&trees.span, &mut spans, cutoff));
+                    current_span.start = current_span.start.max(cutoff);
+                    orphans.retain(|block_number, _| *block_number >= cutoff);
+                    let mut list = removal_list::<R>(&trees);
+                    list.push((trees.failed.clone(), false));
+                    push_removal(&mut removals, Removal::new(list, 0, cutoff - 1, false));
+                    indexer.notify_status_subscribers();
+                }
+            }
+            result = async { removals[0].task.as_mut().unwrap().await }, if !removals.is_empty() => {
+                let (keys, next) = try_or_break!('index, try_or_break!('index, result));
+                let _counts = trees.lock_counts();
+                if !try_or_break!('index, removals[0].remove_chunk(&trees.count, keys, next)) {
+                    let removal = removals.pop_front().unwrap();
+                    match removal.is_reindex {
+                        true => {
+                            try_or_break!('index, trees.chain.remove_blocks(&trees.count, removal.start, removal.end));
+                            retry_from = Some(retry_from.map_or(removal.start, |from| from.min(removal.start)));
+                        },
+                        false => {
+                            try_or_break!('index, trees.chain.prune(&trees.count, removal.end + 1));
+                            info!(
+                                "🗑️  Pruned blocks before #{}",
+                                (removal.end + 1).to_formatted_string(&Locale::en)
+                            );
+                        },
+                    }
+                    if let Some(removal) = removals.front_mut() {
+                        removal.scan(None);
+                    }
+                }
             }
             _ = interval.tick(), if is_batching => {
                 let current_time = Instant::now();
@@ -468,48 +1868,142 @@ pub async fn substrate_index<R: RuntimeIndexer>(
                 stats_key_count = 0;
                 stats_start_time = current_time;
             }
-            (result, index, _) = future::select_all(&mut futures), if is_batching => {
-                match result {
-                    Ok((block_number, event_count, key_count)) => {
-                        // Is the new block contiguous to the current span or an orphan?
-                        if block_number == current_span.start - 1 {
-                            current_span.start = block_number;
-                            debug!("⬇️  Block #{} indexed.", block_number.to_formatted_string(&Locale::en));
-                            check_span(&trees.span, &mut spans, &mut current_span)?;
-                            // Check if any orphans are now contiguous.
-                            while orphans.contains_key(&(current_span.start - 1)) {
-                                current_span.start -= 1;
-                                orphans.remove(&current_span.start);
-                                debug!("➡️  Block #{} unorphaned.", current_span.start.to_formatted_string(&Locale::en));
-                                check_span(&trees.span, &mut spans, &mut current_span)?;
-                            }
+            _ = retry_interval.tick(), if retry_from.is_none() && retry_queue.is_empty() => {
+                retry_from = Some(0);
+            }
+            _ = async {}, if retrying.len() < queue_depth.try_into().unwrap() && (!retry_queue.is_empty() || retry_from.is_some()) => {
+                // Failed blocks are read a few at a time, as there can be many of them.
+                if retry_queue.is_empty() {
+                    let from = retry_from.take().unwrap();
+                    let keys: Result<Vec<_>, _> = trees.failed.range(from.to_be_bytes()..).keys().take(queue_depth.try_into().unwrap()).collect();
+                    for key in try_or_break!('index, keys) {
+                        let block_number = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+                        retry_from = block_number.checked_add(1);
+                        // Blocks being re-indexed are retried once their keys have been removed.
+                        if !removals.iter().any(|removal| removal.is_reindex && (removal.start..=removal.end).contains(&block_number)) {
+                            retry_queue.push_back(block_number);
                         }
-                        else {
-                            orphans.insert(block_number, ());
-                            debug!("⬇️  Block #{} indexed and orphaned.", block_number.to_formatted_string(&Locale::en));
+                    }
+                    continue;
+                }
+                let block_number = retry_queue.pop_front().unwrap();
+                if block_number >= cutoff && retrying.insert(block_number) {
+                    futures.push(Box::pin(indexer.index_batch_block(BatchAttempt {
+                        block_number,
+                        failures: 0,
+                        is_failed: true,
+                    })));
+                    debug!("🔁 Block #{} queued for retry.", block_number.to_formatted_string(&Locale::en));
+                }
+            }
+            // The futures are only created when polled as select_all() panics when there are none.
+            ((attempt, result), index, _) = async { future::select_all(&mut futures).await }, if !futures.is_empty() => {
+                let block_number = attempt.block_number;
+                // The block is committed together with its failed block entry and span.
+                let mut writes = DbBatch::default();
+                let block = match result {
+                    Ok(block) => {
+                        if attempt.is_failed {
+                            writes.remove(&trees.failed, block_number.to_be_bytes());
                         }
-                        stats_block_count += 1;
-                        stats_event_count += event_count;
-                        stats_key_count += key_count;
+                        Some(block)
+                    },
+                    Err(error) if attempt.failures + 1 < BATCH_ATTEMPTS => {
+                        warn!("📚 Indexing #{} failed, retrying: {:?}", block_number.to_formatted_string(&Locale::en), error);
+                        futures[index] = Box::pin(indexer.index_batch_block(BatchAttempt {
+                            failures: attempt.failures + 1,
+                            ..attempt
+                        }));
+                        continue;
                     },
                     Err(error) => {
-                        match error {
-                            IndexError::BlockNotFound(block_number) => {
-                                error!("📚 Block not found #{}", block_number.to_formatted_string(&Locale::en));
-                                is_batching = false;
-                            },
-                            _ => {
-                                error!("📚 Batch indexing failed: {:?}", error);
-                                is_batching = false;
-                            },
-                        }
+                        error!("📚 Indexing #{} failed: {:?}", block_number.to_formatted_string(&Locale::en), error);
+                        writes.insert(&trees.failed, block_number.to_be_bytes(), format!("{:?}", error));
+                        None
+                    },
+                };
+                // Is the new block contiguous to the current span or an orphan? Failed blocks that are retried are already within a span. Blocks that failed are retried later, so they don't stop the span from growing.
+                let is_contiguous = !attempt.is_failed && block_number == current_span.start - 1;
+                let mut span = current_span.clone();
+                if is_contiguous {
+                    span.start = block_number;
+                    check_span(&trees.span, &mut writes, &mut spans, &mut span);
+                    // Check if any orphans are now contiguous.
+                    while orphans.contains_key(&(span.start - 1)) {
+                        span.start -= 1;
+                        orphans.remove(&span.start);
+                        debug!("➡️  Block #{} unorphaned.", span.start.to_formatted_string(&Locale::en));
+                        check_span(&trees.span, &mut writes, &mut spans, &mut span);
                     }
+                    let value = SpanDbValue {
+                        start: span.start.into(),
+                        version: (R::get_versions().len() - 1).try_into().unwrap(),
+                        index_variant: index_variant.into(),
+                    };
+                    writes.insert(&trees.span, span.end.to_be_bytes(), value.as_bytes());
                 }
-                check_next_batch_block(&spans, &mut next_batch_block);
-                futures[index] = Box::pin(indexer.index_block(next_batch_block));
+                let is_indexed = block.is_some();
+                match block {
+                    Some(block) => {
+                        stats_block_count += 1;
+                        stats_event_count += block.event_count;
+                        stats_key_count += block.key_count;
+                        try_or_break!('index, indexer.commit_block(block, writes));
+                    },
+                    None => try_or_break!('index, trees.root.commit(writes)),
+                }
+                // The span only includes the block once it has been committed.
+                current_span = span;
+                if attempt.is_failed {
+                    if is_indexed {
+                        info!("🔁 Block #{} indexed after failing.", block_number.to_formatted_string(&Locale::en));
+                        // The block can be reported as part of its span now.
+                        indexer.notify_status_subscribers();
+                    }
+                    retrying.remove(&block_number);
+                    drop(futures.remove(index));
+                    continue;
+                }
+                match is_contiguous {
+                    true => debug!("⬇️  Block #{} indexed.", block_number.to_formatted_string(&Locale::en)),
+                    false => {
+                        orphans.insert(block_number, ());
+                        debug!("⬇️  Block #{} indexed and orphaned.", block_number.to_formatted_string(&Locale::en));
+                    },
+                }
+                // Don't index blocks outside the target range or the retention window.
+                if !is_batching || !check_next_batch_block(&spans, &mut next_batch_block, target) || next_batch_block < cutoff {
+                    drop(futures.remove(index));
+                    if is_batching {
+                        info!("📚 Reached the start of the target range.");
+                        is_batching = false;
+                    }
+                    continue;
+                }
+                futures[index] = Box::pin(indexer.index_batch_block(BatchAttempt {
+                    block_number: next_batch_block,
+                    failures: 0,
+                    is_failed: false,
+                }));
                 debug!("⬆️  Block #{} queued.", next_batch_block.to_formatted_string(&Locale::en));
                 next_batch_block -= 1;
             }
         }
+    };
+    if current_span.start <= current_span.end {
+        let value = SpanDbValue {
+            start: current_span.start.into(),
+            version: (R::get_versions().len() - 1).try_into().unwrap(),
+            index_variant: index_variant.into(),
+        };
+        trees
+            .span
+            .insert(current_span.end.to_be_bytes(), value.as_bytes())?;
+        info!(
+            "📚 Recording current indexed span from #{} to #{}",
+            current_span.start.to_formatted_string(&Locale::en),
+            current_span.end.to_formatted_string(&Locale::en)
+        );
     }
+    result
 }
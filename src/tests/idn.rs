@@ -126,6 +126,7 @@ fn check_variant_events<R: RuntimeIndexer>(
                     events.push(Event {
                         block_number,
                         event_index,
+                        block_hash: None,
                     });
                 }
             }
@@ -322,7 +323,7 @@ fn create_test_account(seed: u8) -> AccountId32 {
 // Helper to create & prep test database
 fn setup_test_db() -> (Trees<ChainTrees>, Indexer<IdnTestIndexer>) {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<IdnTestIndexer>(db_config).unwrap();
+    let trees = open_trees::<IdnTestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<IdnTestIndexer>::new_test(trees.clone());
     (trees, indexer)
 }
@@ -405,7 +406,9 @@ async fn test_end_to_end_idn_event_processing() {
     let sub_events = process_msg_get_events_substrate::<IdnTestIndexer>(
         &trees,
         &SubstrateKey::SubscriptionId(subscription_id),
-    );
+        &EventQuery::default(),
+    )
+    .unwrap();
     assert_eq!(sub_events.len(), 1);
     assert_eq!(sub_events[0].block_number, 400);
     assert_eq!(sub_events[0].event_index, 5);
@@ -423,8 +426,7 @@ async fn test_websocket_subscription_to_idn_events() {
 
     // Create channel for subscription messages and responses
     let (_sub_tx, _sub_rx) = mpsc::unbounded_channel::<SubscriptionMessage<ChainKey>>();
-    let (sub_response_tx, mut sub_response_rx) =
-        mpsc::unbounded_channel::<ResponseMessage<ChainKey>>();
+    let (sub_response_tx, mut sub_response_rx) = mpsc::unbounded_channel::<Response<ChainKey>>();
 
     // Create a subscription key
     let subscription_id = {
@@ -437,6 +439,8 @@ async fn test_websocket_subscription_to_idn_events() {
     // Subscribe to events for this subscription ID
     let _subscription_msg = SubscriptionMessage::SubscribeEvents {
         key: sub_key.clone(),
+        range: BlockRange::default(),
+        id: None,
         sub_response_tx: sub_response_tx.clone(),
     };
 
@@ -470,7 +474,9 @@ async fn test_idn_event_edge_cases() {
     let events = process_msg_get_events_substrate::<IdnTestIndexer>(
         &trees,
         &SubstrateKey::SubscriptionId(subscription_id),
-    );
+        &EventQuery::default(),
+    )
+    .unwrap();
     assert_eq!(events.len(), 3);
 
     // Test: Beacon events (they only have variant indexing)
@@ -510,7 +516,9 @@ async fn test_ideal_network_indexer_process_event() {
             bytes[28..32].copy_from_slice(&sub_id.to_be_bytes());
             SubscriptionId::from(bytes)
         }),
-    );
+        &EventQuery::default(),
+    )
+    .unwrap();
     assert_eq!(sub_events.len(), 1);
     assert_eq!(sub_events[0].block_number, 600);
     assert_eq!(sub_events[0].event_index, 1);
@@ -519,7 +527,9 @@ async fn test_ideal_network_indexer_process_event() {
     let account_events = process_msg_get_events_substrate::<IdnTestIndexer>(
         &trees,
         &SubstrateKey::AccountId(Bytes32(account.0)),
-    );
+        &EventQuery::default(),
+    )
+    .unwrap();
     assert_eq!(account_events.len(), 1);
     assert_eq!(account_events[0].block_number, 600);
     assert_eq!(account_events[0].event_index, 1);
@@ -5,10 +5,10 @@ use crate::substrate::*;
 use crate::websockets::*;
 use crate::*;
 
+use crate::storage::{Batch, Db, DbBatch, StorageError, Tree};
 use hex_literal::hex;
 use serde::{Deserialize, Serialize};
-use sled::{Db, Tree};
-use std::str::FromStr;
+use std::{ops::Bound, str::FromStr, time::Duration};
 use subxt::utils::AccountId32;
 use tokio::sync::mpsc::{error::TryRecvError, unbounded_channel};
 use zerocopy::{AsBytes, FromBytes};
@@ -22,18 +22,22 @@ pub struct ChainTrees {
 }
 
 impl IndexTrees for ChainTrees {
-    fn open(db: &Db) -> Result<Self, sled::Error> {
+    fn open(db: &Db) -> Result<Self, StorageError> {
         Ok(ChainTrees {
             test_index: db.open_tree(b"test_index")?,
             test_hash: db.open_tree(b"candiate_hash")?,
         })
     }
 
-    fn flush(&self) -> Result<(), sled::Error> {
+    fn flush(&self) -> Result<(), StorageError> {
         self.test_index.flush()?;
         self.test_hash.flush()?;
         Ok(())
     }
+
+    fn trees(&self) -> Vec<&Tree> {
+        vec![&self.test_index, &self.test_hash]
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
@@ -49,9 +53,10 @@ impl IndexKey for ChainKey {
     fn write_db_key(
         &self,
         trees: &ChainTrees,
+        batch: &mut IndexBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) -> Result<(), StorageError> {
         let block_number = block_number.into();
         let event_index = event_index.into();
         match self {
@@ -61,7 +66,7 @@ impl IndexKey for ChainKey {
                     block_number,
                     event_index,
                 };
-                trees.test_index.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.test_index, batch, key.as_bytes())
             }
             ChainKey::TestHash(test_hash) => {
                 let key = Bytes32Key {
@@ -69,16 +74,38 @@ impl IndexKey for ChainKey {
                     block_number,
                     event_index,
                 };
-                trees.test_hash.insert(key.as_bytes(), &[])?
+                write_event_key(&trees.test_hash, batch, key.as_bytes())
             }
         };
         Ok(())
     }
 
-    fn get_key_events(&self, trees: &ChainTrees) -> Vec<Event> {
+    fn get_key_events(
+        &self,
+        trees: &ChainTrees,
+        query: &EventQuery,
+    ) -> Result<Vec<Event>, StorageError> {
+        match self {
+            ChainKey::TestIndex(test_index) => {
+                get_events_u32(&trees.test_index, *test_index, query)
+            }
+            ChainKey::TestHash(test_hash) => get_events_bytes32(&trees.test_hash, test_hash, query),
+        }
+    }
+
+    fn count_key_events(
+        &self,
+        trees: &ChainTrees,
+        count: &Tree,
+        range: &BlockRange,
+    ) -> Result<u64, IndexError> {
         match self {
-            ChainKey::TestIndex(test_index) => get_events_u32(&trees.test_index, *test_index),
-            ChainKey::TestHash(test_hash) => get_events_bytes32(&trees.test_hash, test_hash),
+            ChainKey::TestIndex(test_index) => {
+                count_events(&trees.test_index, count, &test_index.to_be_bytes(), range)
+            }
+            ChainKey::TestHash(test_hash) => {
+                count_events(&trees.test_hash, count, test_hash.as_ref(), range)
+            }
         }
     }
 }
@@ -145,10 +172,38 @@ impl RuntimeIndexer for TestIndexer2 {
     }
 }
 
+/// RPC client for tests that don't need a node
+struct OfflineRpcClient;
+
+impl subxt::backend::rpc::RpcClientT for OfflineRpcClient {
+    fn request_raw<'a>(
+        &'a self,
+        _method: &'a str,
+        _params: Option<Box<subxt::backend::rpc::RawValue>>,
+    ) -> subxt::backend::rpc::RawRpcFuture<'a, Box<subxt::backend::rpc::RawValue>> {
+        Box::pin(async { Err(subxt::error::RpcError::RequestRejected("offline".into())) })
+    }
+
+    fn subscribe_raw<'a>(
+        &'a self,
+        _sub: &'a str,
+        _params: Option<Box<subxt::backend::rpc::RawValue>>,
+        _unsub: &'a str,
+    ) -> subxt::backend::rpc::RawRpcFuture<'a, subxt::backend::rpc::RawRpcSubscription> {
+        Box::pin(async { Err(subxt::error::RpcError::RequestRejected("offline".into())) })
+    }
+}
+
+fn offline_rpc() -> subxt::backend::legacy::LegacyRpcMethods<subxt::PolkadotConfig> {
+    subxt::backend::legacy::LegacyRpcMethods::new(subxt::backend::rpc::RpcClient::new(
+        OfflineRpcClient,
+    ))
+}
+
 #[tokio::test]
 async fn test_process_msg_status() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
 
     let value = SpanDbValue {
         start: 0_u32.try_into().unwrap(),
@@ -180,7 +235,13 @@ async fn test_process_msg_status() {
         .insert(52_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
 
-    let response = process_msg_status::<TestIndexer>(&trees.span);
+    let target = BlockRange {
+        from_block: Some(10),
+        to_block: None,
+    };
+    target.save_target(&trees.root).unwrap();
+
+    let response = process_msg_status::<TestIndexer>(&trees);
 
     let ResponseMessage::Status(spans) = response else {
         panic!("Wrong response message.");
@@ -192,12 +253,76 @@ async fn test_process_msg_status() {
     assert_eq!(spans[1].end, 52);
     assert_eq!(spans[2].start, 60);
     assert_eq!(spans[2].end, 92);
+
+    // Failed blocks are left out of the spans.
+    for block_number in [42_u32, 45, 46, 92] {
+        trees
+            .failed
+            .insert(block_number.to_be_bytes(), "error")
+            .unwrap();
+    }
+    let ResponseMessage::Status(spans) = process_msg_status::<TestIndexer>(&trees) else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(
+        spans,
+        vec![
+            Span { start: 0, end: 40 },
+            Span { start: 43, end: 44 },
+            Span { start: 47, end: 52 },
+            Span { start: 60, end: 91 },
+        ]
+    );
+
+    let response = process_msg_target::<TestIndexer>(&trees).unwrap();
+    let ResponseMessage::Target(status_target) = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(status_target, target);
+    assert_eq!(
+        serde_json::to_string(&ResponseMessage::<ChainKey>::Target(target)).unwrap(),
+        r#"{"type":"target","data":{"fromBlock":10,"toBlock":null}}"#
+    );
+}
+
+#[tokio::test]
+async fn test_process_request_id() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let rpc = offline_rpc();
+    let (sub_tx, _sub_rx) = unbounded_channel();
+    let (sub_response_tx, _sub_response_rx) = unbounded_channel();
+
+    let msg =
+        tokio_tungstenite::tungstenite::Message::Text("{\"id\":1,\"type\":\"Status\"}".into());
+    let response =
+        process_request::<TestIndexer>(&rpc, &trees, &msg, &sub_tx, &sub_response_tx).await;
+    assert_eq!(response.id, Some(RequestId::Number(1)));
+    let ResponseMessage::Status(_) = response.msg else {
+        panic!("Wrong response message.");
+    };
+
+    let msg = tokio_tungstenite::tungstenite::Message::Text(
+        "{\"id\":\"a\",\"type\":\"GetEvents\",\"key\":{\"type\":\"Variant\",\"value\":[0,1]}}"
+            .into(),
+    );
+    let response =
+        process_request::<TestIndexer>(&rpc, &trees, &msg, &sub_tx, &sub_response_tx).await;
+    assert_eq!(response.id, Some(RequestId::String("a".into())));
+    let ResponseMessage::Events { .. } = response.msg else {
+        panic!("Wrong response message.");
+    };
+
+    let msg = tokio_tungstenite::tungstenite::Message::Text("{\"type\":\"Status\"}".into());
+    let response =
+        process_request::<TestIndexer>(&rpc, &trees, &msg, &sub_tx, &sub_response_tx).await;
+    assert_eq!(response.id, None);
 }
 
 #[tokio::test]
 async fn test_process_msg_subscribe_status() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let (sub_tx, mut sub_rx) = unbounded_channel();
     let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
@@ -212,7 +337,7 @@ async fn test_process_msg_subscribe_status() {
         .insert(40_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
 
-    let response = process_msg_subscribe_status::<TestIndexer>(&sub_tx, &sub_response_tx);
+    let response = process_msg_subscribe_status::<TestIndexer>(None, &sub_tx, &sub_response_tx);
 
     let ResponseMessage::Subscribed = response else {
         panic!("Wrong response message.");
@@ -222,7 +347,7 @@ async fn test_process_msg_subscribe_status() {
     process_sub_msg(&indexer, msg);
     indexer.notify_status_subscribers();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
+    let response_msg = sub_response_rx.recv().await.unwrap().msg;
 
     let ResponseMessage::Status(spans) = response_msg else {
         panic!("Wrong response message.");
@@ -243,7 +368,7 @@ async fn test_process_msg_subscribe_status() {
 
     indexer.notify_status_subscribers();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
+    let response_msg = sub_response_rx.recv().await.unwrap().msg;
 
     let ResponseMessage::Status(spans) = response_msg else {
         panic!("Wrong response message.");
@@ -266,7 +391,7 @@ async fn test_process_msg_subscribe_status() {
 
     indexer.notify_status_subscribers();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
+    let response_msg = sub_response_rx.recv().await.unwrap().msg;
 
     let ResponseMessage::Status(spans) = response_msg else {
         panic!("Wrong response message.");
@@ -279,7 +404,7 @@ async fn test_process_msg_subscribe_status() {
     assert_eq!(spans[2].start, 60);
     assert_eq!(spans[2].end, 92);
 
-    let response = process_msg_unsubscribe_status::<TestIndexer>(&sub_tx, &sub_response_tx);
+    let response = process_msg_unsubscribe_status::<TestIndexer>(None, &sub_tx, &sub_response_tx);
 
     let ResponseMessage::Unsubscribed = response else {
         panic!("Wrong response message.");
@@ -296,6 +421,360 @@ async fn test_process_msg_subscribe_status() {
     };
 }
 
+fn check_storage(db: Db) {
+    let tree = db.open_tree(b"test").unwrap();
+    assert_eq!(tree.name(), b"test");
+    assert!(tree.is_empty());
+    assert_eq!(tree.insert([1, 2], [5]).unwrap(), None);
+    assert_eq!(tree.insert([1, 2], [6]).unwrap(), Some(vec![5]));
+    tree.insert([1, 3], [7]).unwrap();
+    tree.insert([1, 255], [8]).unwrap();
+    tree.insert([2, 0], [9]).unwrap();
+    assert_eq!(tree.len(), 4);
+    assert_eq!(tree.get([1, 2]).unwrap(), Some(vec![6]));
+    assert!(!tree.contains_key([1, 4]).unwrap());
+
+    let keys: Vec<_> = tree.scan_prefix([1]).keys().map(Result::unwrap).collect();
+    assert_eq!(keys, vec![vec![1, 2], vec![1, 3], vec![1, 255]]);
+    let keys: Vec<_> = tree
+        .range::<&[u8], _>((Bound::Excluded(&[1, 2][..]), Bound::Unbounded))
+        .keys()
+        .rev()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(keys, vec![vec![2, 0], vec![1, 255], vec![1, 3]]);
+    // Inverted ranges are empty.
+    assert_eq!(tree.range(&[2, 0][..]..=&[1, 2][..]).count(), 0);
+    assert_eq!(
+        tree.range::<&[u8], _>((Bound::Excluded(&[1, 3][..]), Bound::Excluded(&[1, 3][..])))
+            .count(),
+        0
+    );
+    assert_eq!(
+        tree.range::<&[u8], _>((Bound::Included(&[1, 3][..]), Bound::Excluded(&[1, 3][..])))
+            .count(),
+        0
+    );
+    assert_eq!(tree.first().unwrap(), Some((vec![1, 2], vec![6])));
+    assert_eq!(tree.last().unwrap(), Some((vec![2, 0], vec![9])));
+
+    let value = tree
+        .update_and_fetch([3], |value| {
+            Some(vec![value.map_or(0, |value| value[0]) + 1])
+        })
+        .unwrap();
+    assert_eq!(value, Some(vec![1]));
+
+    let mut batch = Batch::default();
+    batch.insert([4], [1]);
+    batch.remove([1, 2]);
+    tree.apply_batch(batch).unwrap();
+    assert_eq!(tree.get([4]).unwrap(), Some(vec![1]));
+    assert_eq!(tree.remove([1, 2]).unwrap(), None);
+
+    let other = db.open_tree(b"other").unwrap();
+    let mut batch = DbBatch::default();
+    batch.insert(&tree, [5], [2]);
+    batch.remove(&tree, [4]);
+    batch.insert(&other, [6], [3]);
+    db.commit(batch).unwrap();
+    assert_eq!(tree.get([5]).unwrap(), Some(vec![2]));
+    assert!(!tree.contains_key([4]).unwrap());
+    assert_eq!(other.get([6]).unwrap(), Some(vec![3]));
+
+    // Large ranges can be iterated from both ends.
+    let large = db.open_tree(b"large").unwrap();
+    let keys: Vec<Vec<u8>> = (0..2500_u16)
+        .map(|key| key.to_be_bytes().to_vec())
+        .collect();
+    for key in &keys {
+        large.insert(key, []).unwrap();
+    }
+    let mut iter = large
+        .range(keys[1].as_slice()..keys[2499].as_slice())
+        .keys();
+    let mut front = vec![];
+    let mut back = vec![];
+    for i in 0.. {
+        let key = match i % 3 {
+            0 => iter.next_back().map(|key| (&mut back, key)),
+            _ => iter.next().map(|key| (&mut front, key)),
+        };
+        let Some((keys, key)) = key else {
+            break;
+        };
+        keys.push(key.unwrap());
+        // The tree can be written to while it is being iterated.
+        large.insert([u8::MAX], []).unwrap();
+    }
+    back.reverse();
+    front.extend(back);
+    assert_eq!(front, keys[1..2499]);
+
+    // Trees with the same name are the same tree.
+    assert_eq!(db.open_tree(b"test").unwrap().len(), tree.len());
+    db.insert("genesis_hash", [1]).unwrap();
+    assert_eq!(db.get("genesis_hash").unwrap(), Some(vec![1]));
+    tree.clear().unwrap();
+    assert!(tree.is_empty());
+    db.flush().unwrap();
+}
+
+#[test]
+fn test_storage_sled() {
+    let db_config = sled::Config::new().temporary(true);
+    check_storage(Db::open_sled(db_config).unwrap());
+}
+
+#[test]
+fn test_storage_memory() {
+    check_storage(Db::memory());
+}
+
+#[test]
+fn test_process_msg_tree_stats() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    indexer
+        .index_event(Key::Substrate(SubstrateKey::AccountIndex(3)), 8, 5)
+        .unwrap();
+    indexer
+        .index_event(Key::Substrate(SubstrateKey::AccountIndex(4)), 4, 1)
+        .unwrap();
+    indexer
+        .index_event(Key::Chain(ChainKey::TestIndex(3)), 10, 1)
+        .unwrap();
+    let value = SpanDbValue {
+        start: 2_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(12_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+
+    let response = process_msg_tree_stats::<TestIndexer>(&trees).unwrap();
+    let ResponseMessage::TreeStats(stats) = response else {
+        panic!("Wrong response message.");
+    };
+    let tree_stats = |name: &str| stats.iter().find(|stats| stats.name == name).unwrap();
+    assert_eq!(
+        *tree_stats("span"),
+        TreeStats {
+            name: "span".into(),
+            entries: 1,
+            size: 11,
+            first_block: Some(2),
+            last_block: Some(12),
+        }
+    );
+    assert_eq!(
+        *tree_stats("account_index"),
+        TreeStats {
+            name: "account_index".into(),
+            entries: 2,
+            size: 20,
+            first_block: Some(4),
+            last_block: Some(8),
+        }
+    );
+    assert_eq!(tree_stats("test_index").entries, 1);
+    assert_eq!(tree_stats("test_index").first_block, Some(10));
+    assert_eq!(tree_stats("count").entries, 3);
+    assert_eq!(tree_stats("count").first_block, None);
+    assert_eq!(tree_stats("era_index").entries, 0);
+    assert_eq!(tree_stats("era_index").last_block, None);
+    // Keys too short to hold a block number are counted without a block range.
+    trees.substrate.era_index.insert([1, 2], []).unwrap();
+    trees.failed.insert([1], []).unwrap();
+    let response = process_msg_tree_stats::<TestIndexer>(&trees).unwrap();
+    let ResponseMessage::TreeStats(stats) = response else {
+        panic!("Wrong response message.");
+    };
+    let tree_stats = |name: &str| stats.iter().find(|stats| stats.name == name).unwrap();
+    assert_eq!(tree_stats("era_index").entries, 1);
+    assert_eq!(tree_stats("era_index").last_block, None);
+    assert_eq!(tree_stats("failed").entries, 1);
+    assert_eq!(tree_stats("failed").first_block, None);
+}
+
+#[test]
+fn test_process_msg_failed_blocks() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    trees
+        .failed
+        .insert(8_u32.to_be_bytes(), "BlockNotFound(8)")
+        .unwrap();
+    trees
+        .failed
+        .insert(12_u32.to_be_bytes(), "ParseError")
+        .unwrap();
+
+    let response = process_msg_failed_blocks::<TestIndexer>(&trees).unwrap();
+    let ResponseMessage::FailedBlocks(failed_blocks) = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(
+        failed_blocks,
+        vec![
+            FailedBlock {
+                block_number: 8,
+                error: "BlockNotFound(8)".into(),
+            },
+            FailedBlock {
+                block_number: 12,
+                error: "ParseError".into(),
+            },
+        ]
+    );
+
+    // Failed blocks outside the retention window are not retried.
+    prune_trees::<TestIndexer>(&trees, &mut vec![], 10).unwrap();
+    assert_eq!(trees.failed.len(), 1);
+}
+
+#[test]
+fn test_commit_batch() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let key = Key::<ChainKey>::Substrate(SubstrateKey::AccountIndex(3));
+    let mut batch = IndexBatch::default();
+    key.write_db_key(&trees, &mut batch, 4, 5).unwrap();
+    key.write_db_key(&trees, &mut batch, 8, 5).unwrap();
+    // The same event in a batch is only counted once.
+    key.write_db_key(&trees, &mut batch, 8, 5).unwrap();
+    batch
+        .writes
+        .insert(&trees.block_hash, 8_u32.to_be_bytes(), [8; 32]);
+    // Nothing is written until the batch is committed.
+    assert!(trees.substrate.account_index.is_empty());
+    commit_batch(&trees, batch).unwrap();
+    assert_eq!(trees.substrate.account_index.len(), 2);
+    assert_eq!(trees.block_hash.len(), 1);
+
+    // Events that have already been indexed are not counted again.
+    let mut batch = IndexBatch::default();
+    key.write_db_key(&trees, &mut batch, 8, 5).unwrap();
+    key.write_db_key(&trees, &mut batch, 10, 1).unwrap();
+    commit_batch(&trees, batch).unwrap();
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key.clone(), BlockRange::default())
+            .unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 3);
+
+    // Concurrent commits don't lose event counts.
+    std::thread::scope(|scope| {
+        for block_number in 20..28 {
+            let (trees, key) = (&trees, &key);
+            scope.spawn(move || {
+                let mut batch = IndexBatch::default();
+                key.write_db_key(trees, &mut batch, block_number, 0)
+                    .unwrap();
+                commit_batch(trees, batch).unwrap();
+            });
+        }
+    });
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 11);
+}
+
+#[test]
+fn test_parent_hash() {
+    use subxt::config::substrate::{BlakeTwo256, Digest, SubstrateHeader, H256};
+    let header = SubstrateHeader::<u32, BlakeTwo256> {
+        parent_hash: H256([1; 32]),
+        number: 5,
+        state_root: H256([2; 32]),
+        extrinsics_root: H256([3; 32]),
+        digest: Digest::default(),
+    };
+    assert_eq!(header.parent_hash(), H256([1; 32]));
+}
+
+#[tokio::test]
+async fn test_migration() {
+    use crate::migration::*;
+    let db = Db::memory();
+    let trees = open_trees::<TestIndexer>(db.clone()).unwrap();
+    assert_eq!(get_schema_version(&trees).unwrap(), Some(SCHEMA_VERSION));
+    // Simulate a database created before events were counted.
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
+    indexer
+        .index_event(Key::Chain(ChainKey::TestIndex(3)), 8, 1)
+        .unwrap();
+    trees.count.clear().unwrap();
+    trees.root.remove(SCHEMA_VERSION_KEY).unwrap();
+    trees.root.insert("genesis_hash", [1]).unwrap();
+    assert_eq!(get_schema_version(&trees).unwrap(), Some(0));
+
+    let trees = open_trees::<TestIndexer>(db.clone()).unwrap();
+    assert_eq!(get_schema_version(&trees).unwrap(), Some(SCHEMA_VERSION));
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 2);
+    let key = Key::Chain(ChainKey::TestIndex(3));
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 1);
+    let key = Key::Variant(0, 0);
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 0);
+
+    // Newer databases are refused.
+    set_schema_version(&trees, SCHEMA_VERSION + 1).unwrap();
+    let Err(StorageError::IncompatibleSchema(version, supported)) = open_trees::<TestIndexer>(db)
+    else {
+        panic!("Opened incompatible database.");
+    };
+    assert_eq!(version, SCHEMA_VERSION + 1);
+    assert_eq!(supported, SCHEMA_VERSION);
+}
+
+#[tokio::test]
+async fn test_process_msg_get_events_memory() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Chain(ChainKey::TestIndex(7));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
+    let ResponseMessage::Events { events, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[1].block_number, 4);
+
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 2);
+}
+
 #[test]
 fn test_variant_key() {
     let key1 = VariantKey {
@@ -312,18 +791,20 @@ fn test_variant_key() {
 #[tokio::test]
 async fn test_process_msg_variant() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let key = Key::Variant(3, 65);
     indexer.index_event(key.clone(), 4, 5).unwrap();
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -352,7 +833,7 @@ fn test_bytes32_key() {
 #[tokio::test]
 async fn test_process_msg_account_id() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let account_id =
         AccountId32::from_str("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY").unwrap();
@@ -361,11 +842,13 @@ async fn test_process_msg_account_id() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -392,7 +875,7 @@ fn test_u32_key() {
 #[tokio::test]
 async fn test_process_msg_account_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let account_index = 88;
     let key = Key::Substrate(SubstrateKey::AccountIndex(account_index));
@@ -400,11 +883,13 @@ async fn test_process_msg_account_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -419,7 +904,7 @@ async fn test_process_msg_account_index() {
 #[tokio::test]
 async fn test_process_msg_bounty_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let bounty_index = 88;
     let key = Key::Substrate(SubstrateKey::BountyIndex(bounty_index));
@@ -427,11 +912,13 @@ async fn test_process_msg_bounty_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -446,7 +933,7 @@ async fn test_process_msg_bounty_index() {
 #[tokio::test]
 async fn test_process_msg_era_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let era_index = 88;
     let key = Key::Substrate(SubstrateKey::EraIndex(era_index));
@@ -454,11 +941,13 @@ async fn test_process_msg_era_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -473,7 +962,7 @@ async fn test_process_msg_era_index() {
 #[tokio::test]
 async fn test_process_msg_message_id() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let message_id = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::MessageId(message_id));
@@ -481,11 +970,13 @@ async fn test_process_msg_message_id() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -500,7 +991,7 @@ async fn test_process_msg_message_id() {
 #[tokio::test]
 async fn test_process_msg_pool_id() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let pool_id = 88;
     let key = Key::Substrate(SubstrateKey::PoolId(pool_id));
@@ -508,11 +999,13 @@ async fn test_process_msg_pool_id() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -527,7 +1020,7 @@ async fn test_process_msg_pool_id() {
 #[tokio::test]
 async fn test_process_msg_preimage_hash() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let preimage_hash = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::PreimageHash(preimage_hash));
@@ -535,11 +1028,13 @@ async fn test_process_msg_preimage_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -554,7 +1049,7 @@ async fn test_process_msg_preimage_hash() {
 #[tokio::test]
 async fn test_process_msg_proposal_hash() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let proposal_hash = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::ProposalHash(proposal_hash));
@@ -562,11 +1057,13 @@ async fn test_process_msg_proposal_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -581,7 +1078,7 @@ async fn test_process_msg_proposal_hash() {
 #[tokio::test]
 async fn test_process_msg_proposal_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let proposal_index = 88;
     let key = Key::Substrate(SubstrateKey::ProposalIndex(proposal_index));
@@ -589,11 +1086,13 @@ async fn test_process_msg_proposal_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -608,7 +1107,7 @@ async fn test_process_msg_proposal_index() {
 #[tokio::test]
 async fn test_process_msg_ref_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let ref_index = 88;
     let key = Key::Substrate(SubstrateKey::RefIndex(ref_index));
@@ -616,11 +1115,13 @@ async fn test_process_msg_ref_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -635,7 +1136,7 @@ async fn test_process_msg_ref_index() {
 #[tokio::test]
 async fn test_process_msg_registrar_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let registrar_index = 88;
     let key = Key::Substrate(SubstrateKey::RegistrarIndex(registrar_index));
@@ -643,11 +1144,13 @@ async fn test_process_msg_registrar_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -662,7 +1165,7 @@ async fn test_process_msg_registrar_index() {
 #[tokio::test]
 async fn test_process_msg_session_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let session_index = 88;
     let key = Key::Substrate(SubstrateKey::SessionIndex(session_index));
@@ -670,11 +1173,13 @@ async fn test_process_msg_session_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -689,7 +1194,7 @@ async fn test_process_msg_session_index() {
 #[tokio::test]
 async fn test_process_msg_tip_hash() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let tip_hash = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::TipHash(tip_hash));
@@ -697,11 +1202,13 @@ async fn test_process_msg_tip_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -716,7 +1223,7 @@ async fn test_process_msg_tip_hash() {
 #[tokio::test]
 async fn test_process_msg_chain_test_index() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let test_index = 88;
     let key = Key::Chain(ChainKey::TestIndex(test_index));
@@ -724,11 +1231,13 @@ async fn test_process_msg_chain_test_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -743,7 +1252,7 @@ async fn test_process_msg_chain_test_index() {
 #[tokio::test]
 async fn test_process_msg_chain_test_hash() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let test_hash = Bytes32([8; 32]);
     let key = Key::Chain(ChainKey::TestHash(test_hash));
@@ -751,11 +1260,13 @@ async fn test_process_msg_chain_test_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -768,76 +1279,912 @@ async fn test_process_msg_chain_test_hash() {
 }
 
 #[tokio::test]
-async fn test_process_msg_subscribe_events() {
+async fn test_process_msg_get_events_pages() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
-    let (sub_tx, mut sub_rx) = unbounded_channel();
-    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
-    let key = Key::Variant(3, 65);
+    let key = Key::Substrate(SubstrateKey::AccountIndex(88));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+    indexer
+        .index_event(Key::Substrate(SubstrateKey::AccountIndex(89)), 2, 0)
+        .unwrap();
 
-    let response =
-        process_msg_subscribe_events::<TestIndexer>(key.clone(), &sub_tx, &sub_response_tx);
+    let query = EventQuery {
+        limit: Some(2),
+        ..EventQuery::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
 
-    let ResponseMessage::Subscribed = response else {
+    let ResponseMessage::Events { events, next, .. } = response else {
         panic!("Wrong response message.");
     };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(events[1].block_number, 8);
+    assert_eq!(events[1].event_index, 5);
+    assert_eq!(next, Some(events[1].clone()));
 
-    let msg = sub_rx.recv().await.unwrap();
-    process_sub_msg(&indexer, msg);
+    let query = EventQuery {
+        before: next,
+        limit: Some(2),
+        ..EventQuery::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
 
-    indexer.index_event(key.clone(), 4, 5).unwrap();
+    let ResponseMessage::Events { events, next, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[0].event_index, 2);
+    assert_eq!(events[1].block_number, 4);
+    assert_eq!(next, None);
+
+    // Limits are clamped to between 1 and EVENTS_LIMIT_MAX.
+    assert_eq!(EventQuery::default().limit(), 100);
+    let query = EventQuery {
+        limit: Some(EVENTS_LIMIT_MAX + 1),
+        ..EventQuery::default()
+    };
+    assert_eq!(query.limit(), 1000);
+    let query = EventQuery {
+        limit: Some(0),
+        ..EventQuery::default()
+    };
+    assert_eq!(query.limit(), 1);
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
-    let ResponseMessage::Events {
-        key: response_key,
-        events,
-    } = response_msg
-    else {
+    let ResponseMessage::Events { events, next, .. } = response else {
         panic!("Wrong response message.");
     };
-    assert_eq!(key, response_key);
     assert_eq!(events.len(), 1);
-    assert_eq!(events[0].block_number, 4);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(next, Some(events[0].clone()));
+}
 
+#[tokio::test]
+async fn test_process_msg_get_events_range() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Chain(ChainKey::TestHash(Bytes32([8; 32])));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
     indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+    indexer.index_event(key.clone(), 12, 0).unwrap();
+
+    let query = EventQuery {
+        range: BlockRange {
+            from_block: Some(8),
+            to_block: Some(10),
+        },
+        ..EventQuery::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
-    let ResponseMessage::Events {
-        key: response_key,
-        events,
-    } = response_msg
-    else {
+    let ResponseMessage::Events { events, next, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(events[1].block_number, 8);
+    assert_eq!(events[1].event_index, 5);
+    assert_eq!(events[2].block_number, 8);
+    assert_eq!(events[2].event_index, 2);
+    assert_eq!(next, None);
+
+    let query = EventQuery {
+        before: Some(Event {
+            block_number: 8,
+            event_index: 5,
+            block_hash: None,
+        }),
+        range: BlockRange {
+            from_block: Some(5),
+            to_block: Some(11),
+        },
+        ..EventQuery::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
+
+    let ResponseMessage::Events { events, .. } = response else {
         panic!("Wrong response message.");
     };
-    assert_eq!(key, response_key);
     assert_eq!(events.len(), 1);
     assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[0].event_index, 2);
+
+    // Inverted ranges contain no events.
+    let query = EventQuery {
+        range: BlockRange {
+            from_block: Some(10),
+            to_block: Some(8),
+        },
+        ..EventQuery::default()
+    };
+    assert_eq!(event_range(&[8; 32], &query), None);
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
+    let ResponseMessage::Events { events, next, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 0);
+    assert_eq!(next, None);
+
+    let event = Event {
+        block_number: 8,
+        event_index: 5,
+        block_hash: None,
+    };
+    let query = EventQuery {
+        before: Some(event.clone()),
+        after: Some(event),
+        ..EventQuery::default()
+    };
+    assert_eq!(event_range(&[8; 32], &query), None);
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
+    let ResponseMessage::Events { events, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 0);
+}
 
+#[tokio::test]
+async fn test_process_msg_count_events() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
+    // Indexing the same event again should not change the count.
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+    indexer
+        .index_event(Key::Substrate(SubstrateKey::AccountIndex(4)), 8, 1)
+        .unwrap();
+    indexer
+        .index_event(Key::Substrate(SubstrateKey::EraIndex(3)), 8, 1)
+        .unwrap();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
-    let ResponseMessage::Events {
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key.clone(), BlockRange::default())
+            .unwrap();
+    let ResponseMessage::EventCount {
         key: response_key,
-        events,
-    } = response_msg
+        count,
+    } = response
     else {
         panic!("Wrong response message.");
     };
-    assert_eq!(key, response_key);
-    assert_eq!(events.len(), 1);
-    assert_eq!(events[0].block_number, 10);
-
-    let response =
-        process_msg_unsubscribe_events::<TestIndexer>(key.clone(), &sub_tx, &sub_response_tx);
+    assert_eq!(response_key, key);
+    assert_eq!(count, 4);
 
-    let ResponseMessage::Unsubscribed = response else {
+    let range = BlockRange {
+        from_block: Some(5),
+        to_block: Some(8),
+    };
+    let response = process_msg_count_events::<TestIndexer>(&trees, key, range).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
         panic!("Wrong response message.");
     };
+    assert_eq!(count, 2);
+
+    let key = Key::Chain(ChainKey::TestIndex(3));
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key.clone(), BlockRange::default())
+            .unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 0);
+    indexer.index_event(key.clone(), 9, 1).unwrap();
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn test_process_msg_get_events_block_hash() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Variant(3, 65);
+    trees
+        .block_hash
+        .insert(8_u32.to_be_bytes(), [8; 32])
+        .unwrap();
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default()).unwrap();
+    let ResponseMessage::Events { events, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_hash, Some(Bytes32([8; 32])));
+    assert_eq!(events[1].block_hash, None);
+
+    let json = serde_json::to_string(&events).unwrap();
+    let expected = format!(
+        "[{{\"blockNumber\":8,\"eventIndex\":5,\"blockHash\":\"0x{}\"}},{{\"blockNumber\":4,\"eventIndex\":5}}]",
+        "08".repeat(32)
+    );
+    assert_eq!(json, expected);
+}
+
+#[tokio::test]
+async fn test_process_msg_get_events_asc() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Variant(3, 65);
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+
+    let query = EventQuery {
+        order: Order::Asc,
+        limit: Some(3),
+        ..EventQuery::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
+
+    let ResponseMessage::Events { events, next, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].block_number, 4);
+    assert_eq!(events[1].block_number, 8);
+    assert_eq!(events[1].event_index, 2);
+    assert_eq!(events[2].block_number, 8);
+    assert_eq!(events[2].event_index, 5);
+    assert_eq!(next, Some(events[2].clone()));
+
+    let query = EventQuery {
+        after: next,
+        order: Order::Asc,
+        limit: Some(3),
+        ..EventQuery::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), query).unwrap();
+
+    let ResponseMessage::Events { events, next, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(next, None);
+}
+
+#[tokio::test]
+async fn test_process_msg_query() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let account_key = Key::Substrate(SubstrateKey::AccountId(Bytes32([1; 32])));
+    let pool_key = Key::Substrate(SubstrateKey::PoolId(7));
+    let transfer_key = Key::Variant(5, 2);
+    let deposit_key = Key::Variant(5, 7);
+    indexer.index_event(account_key.clone(), 4, 1).unwrap();
+    indexer.index_event(transfer_key.clone(), 4, 1).unwrap();
+    indexer.index_event(account_key.clone(), 6, 3).unwrap();
+    indexer.index_event(pool_key.clone(), 6, 3).unwrap();
+    indexer.index_event(deposit_key.clone(), 6, 3).unwrap();
+    indexer.index_event(pool_key.clone(), 8, 0).unwrap();
+    indexer.index_event(transfer_key.clone(), 9, 2).unwrap();
+    indexer.index_event(account_key.clone(), 10, 0).unwrap();
+    indexer.index_event(pool_key.clone(), 10, 0).unwrap();
+    indexer.index_event(deposit_key.clone(), 10, 0).unwrap();
+
+    let expr = KeyExpr::And(vec![
+        KeyExpr::Key(account_key.clone()),
+        KeyExpr::Key(pool_key.clone()),
+    ]);
+    let response = process_msg_query::<TestIndexer>(&trees, expr, EventQuery::default()).unwrap();
+
+    let ResponseMessage::QueryEvents { events, next } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(events[1].block_number, 6);
+    assert_eq!(events[1].event_index, 3);
+    assert_eq!(next, None);
+
+    let expr = KeyExpr::And(vec![
+        KeyExpr::Or(vec![
+            KeyExpr::Key(transfer_key.clone()),
+            KeyExpr::Key(deposit_key.clone()),
+        ]),
+        KeyExpr::Key(account_key.clone()),
+    ]);
+    let query = EventQuery {
+        order: Order::Asc,
+        limit: Some(2),
+        ..EventQuery::default()
+    };
+    let response = process_msg_query::<TestIndexer>(&trees, expr.clone(), query).unwrap();
+
+    let ResponseMessage::QueryEvents { events, next } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 4);
+    assert_eq!(events[1].block_number, 6);
+    assert_eq!(next, Some(events[1].clone()));
+
+    let query = EventQuery {
+        after: next,
+        order: Order::Asc,
+        limit: Some(2),
+        ..EventQuery::default()
+    };
+    let response = process_msg_query::<TestIndexer>(&trees, expr, query).unwrap();
+
+    let ResponseMessage::QueryEvents { events, next } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(next, None);
+
+    let request: RequestMessage<ChainKey> = serde_json::from_str(
+        r#"{
+            "type": "Query",
+            "expr": {
+                "type": "Or",
+                "value": [
+                    {"type": "Key", "value": {"type": "Substrate", "value": {"type": "PoolId", "value": 7}}},
+                    {"type": "Key", "value": {"type": "Variant", "value": [5, 7]}}
+                ]
+            }
+        }"#,
+    )
+    .unwrap();
+    let RequestMessage::Query { expr, query } = request else {
+        panic!("Wrong request message.");
+    };
+    let response = process_msg_query::<TestIndexer>(&trees, expr, query).unwrap();
+
+    let ResponseMessage::QueryEvents { events, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(events[1].block_number, 8);
+    assert_eq!(events[2].block_number, 6);
+
+    // Match events across several pages of each key.
+    for block_number in 100..350 {
+        indexer
+            .index_event(pool_key.clone(), block_number, 0)
+            .unwrap();
+        if block_number % 2 == 0 {
+            indexer
+                .index_event(account_key.clone(), block_number, 0)
+                .unwrap();
+        }
+    }
+    let expr = KeyExpr::And(vec![KeyExpr::Key(account_key), KeyExpr::Key(pool_key)]);
+    let query = EventQuery {
+        limit: Some(1000),
+        ..EventQuery::default()
+    };
+    let response = process_msg_query::<TestIndexer>(&trees, expr, query).unwrap();
+
+    let ResponseMessage::QueryEvents { events, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 127);
+    assert_eq!(events[0].block_number, 348);
+    assert_eq!(events[124].block_number, 100);
+    assert_eq!(events[126].block_number, 6);
+}
+
+#[test]
+fn test_process_msg_get_event_payloads() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    indexer.store_payload(4, 5, 100, &[0, 1, 2]).unwrap();
+    indexer.store_payload(8, 1, 101, &[3, 4]).unwrap();
+
+    let events = vec![
+        Event {
+            block_number: 8,
+            event_index: 1,
+            block_hash: None,
+        },
+        Event {
+            block_number: 8,
+            event_index: 2,
+            block_hash: None,
+        },
+        Event {
+            block_number: 4,
+            event_index: 5,
+            block_hash: None,
+        },
+    ];
+    let response = process_msg_get_event_payloads::<TestIndexer>(&trees, events).unwrap();
+    let ResponseMessage::EventPayloads(payloads) = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(payloads.len(), 2);
+    assert_eq!(payloads[0].block_number, 8);
+    assert_eq!(payloads[0].event_index, 1);
+    assert_eq!(payloads[0].spec_version, 101);
+    assert_eq!(payloads[0].payload, "0x0304");
+    assert_eq!(payloads[1].block_number, 4);
+    assert_eq!(payloads[1].spec_version, 100);
+    assert_eq!(payloads[1].payload, "0x000102");
+}
+
+#[test]
+fn test_error_response() {
+    let msg = tokio_tungstenite::tungstenite::Message::Text("{\"type\":\"Status\"}".into());
+    let request = parse_request::<ChainKey>(&msg).unwrap();
+    assert_eq!(request.id, None);
+    assert!(matches!(request.msg, RequestMessage::Status));
+
+    let msg = tokio_tungstenite::tungstenite::Message::Text(
+        "{\"id\":\"a\",\"type\":\"GetEvents\",\"key\":{\"type\":\"Variant\",\"value\":[0,1]},\"limit\":2}".into(),
+    );
+    let request = parse_request::<ChainKey>(&msg).unwrap();
+    assert_eq!(request.id, Some(RequestId::String("a".into())));
+    assert!(matches!(request.msg, RequestMessage::GetEvents { .. }));
+    let response = Response::<ChainKey> {
+        id: request.id,
+        msg: ResponseMessage::Subscribed,
+    };
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        "{\"id\":\"a\",\"type\":\"subscribed\"}"
+    );
+
+    let msg = tokio_tungstenite::tungstenite::Message::Text("{\"type\":\"Unknown\"}".into());
+    let error = parse_request::<ChainKey>(&msg).unwrap_err();
+    let response: ResponseMessage<ChainKey> = error.into();
+    let ResponseMessage::Error { code, message } = &response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(*code, ErrorCode::InvalidRequest);
+    assert_eq!(message, "invalid request");
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        "{\"type\":\"error\",\"data\":{\"code\":\"invalidRequest\",\"message\":\"invalid request\"}}"
+    );
+
+    let error = IndexError::ParseError;
+    assert_eq!(ErrorCode::from(&error), ErrorCode::Parse);
+
+    let error = IndexError::BlockNotFound(7);
+    assert_eq!(ErrorCode::from(&error), ErrorCode::BlockNotFound);
+
+    let error = IndexError::Disconnected;
+    assert_eq!(ErrorCode::from(&error), ErrorCode::Connection);
+
+    let error = IndexError::WrongGenesisHash;
+    assert_eq!(ErrorCode::from(&error), ErrorCode::Connection);
+}
+
+#[test]
+fn test_cbor() {
+    let request = serde_json::json!({
+        "id": 4,
+        "type": "GetEvents",
+        "key": {
+            "type": "Substrate",
+            "value": {
+                "type": "AccountId",
+                "value": "0xd43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d",
+            },
+        },
+        "limit": 2,
+    });
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&request, &mut bytes).unwrap();
+    let msg = tokio_tungstenite::tungstenite::Message::Binary(bytes);
+    let request = parse_request::<ChainKey>(&msg).unwrap();
+    assert_eq!(request.id, Some(RequestId::Number(4)));
+    let RequestMessage::GetEvents { key, query } = request.msg else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(
+        key,
+        Key::Substrate(SubstrateKey::AccountId(Bytes32(hex!(
+            "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d"
+        ))))
+    );
+    assert_eq!(query.limit, Some(2));
+
+    let msg = tokio_tungstenite::tungstenite::Message::Binary(vec![0xff, 0x00]);
+    let error = parse_request::<ChainKey>(&msg).unwrap_err();
+    assert_eq!(ErrorCode::from(&error), ErrorCode::InvalidRequest);
+
+    let response = Response::<ChainKey> {
+        id: Some(RequestId::Number(4)),
+        msg: ResponseMessage::SizeOnDisk(42),
+    };
+    let tokio_tungstenite::tungstenite::Message::Binary(bytes) = encode_response(&response, true)
+    else {
+        panic!("Wrong message type.");
+    };
+    let value: serde_json::Value = ciborium::from_reader(bytes.as_slice()).unwrap();
+    assert_eq!(
+        value,
+        serde_json::json!({"id": 4, "type": "sizeOnDisk", "data": 42})
+    );
+    let tokio_tungstenite::tungstenite::Message::Text(_) = encode_response(&response, false) else {
+        panic!("Wrong message type.");
+    };
+}
+
+#[test]
+fn test_jsonrpc() {
+    let value = serde_json::json!({"jsonrpc": "2.0", "method": "index_status", "id": 1});
+    assert!(jsonrpc::is_jsonrpc(&value));
+    assert!(!jsonrpc::is_jsonrpc(&serde_json::json!({"type": "Status"})));
+
+    let params = serde_json::json!({
+        "key": {"type": "Variant", "value": [0, 1]},
+        "limit": 5,
+        "fromBlock": 10,
+    });
+    let msg = jsonrpc::request_msg::<ChainKey>("GetEvents", params.clone()).unwrap();
+    let RequestMessage::GetEvents { key, query } = msg else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(key, Key::Variant(0, 1));
+    assert_eq!(query.limit, Some(5));
+    assert_eq!(query.range.from_block, Some(10));
+    let msg = jsonrpc::request_msg::<ChainKey>("GetEvents", serde_json::json!([params])).unwrap();
+    assert!(matches!(msg, RequestMessage::GetEvents { .. }));
+    let error =
+        jsonrpc::request_msg::<ChainKey>("GetEvents", serde_json::json!([1, 2])).unwrap_err();
+    assert_eq!(error.code, jsonrpc::INVALID_PARAMS);
+    let error = jsonrpc::request_msg::<ChainKey>("GetEvents", serde_json::json!({})).unwrap_err();
+    assert_eq!(error.code, jsonrpc::INVALID_PARAMS);
+    assert_eq!(error.data, Some(ErrorCode::InvalidRequest));
+
+    // Messages meant to be JSON-RPC that are not valid JSON get a parse error.
+    assert!(jsonrpc::is_jsonrpc_text(r#"{"jsonrpc": "2.0", "method""#));
+    assert!(jsonrpc::is_jsonrpc_text(" [{"));
+    assert!(!jsonrpc::is_jsonrpc_text(r#"{"type": "Status""#));
+    let error: serde_json::Value = serde_json::from_str(&jsonrpc::jsonrpc_parse_error()).unwrap();
+    assert_eq!(error["error"]["code"], serde_json::json!(-32700));
+    assert_eq!(error["id"], serde_json::Value::Null);
+
+    let result = jsonrpc::response_result::<ChainKey>(ResponseMessage::SizeOnDisk(42));
+    assert_eq!(result, serde_json::json!(42));
+
+    let notification = jsonrpc::jsonrpc_notification::<ChainKey>(Response {
+        id: Some(RequestId::Number(3)),
+        msg: ResponseMessage::Events {
+            key: Key::Variant(0, 1),
+            events: vec![Event {
+                block_number: 7,
+                event_index: 2,
+                block_hash: None,
+            }],
+            next: None,
+        },
+    });
+    let notification: serde_json::Value = serde_json::from_str(&notification).unwrap();
+    assert_eq!(
+        notification,
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "index_subscription",
+            "params": {
+                "subscription": 3,
+                "result": {
+                    "key": {"type": "Variant", "value": [0, 1]},
+                    "events": [{"blockNumber": 7, "eventIndex": 2}],
+                    "next": null,
+                },
+            },
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_process_jsonrpc() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let rpc = offline_rpc();
+    let (sub_tx, _sub_rx) = unbounded_channel();
+    let (sub_response_tx, _sub_response_rx) = unbounded_channel();
+    let mut subs = jsonrpc::JsonRpcSubscriptions::default();
+    let mut request = async |request: serde_json::Value| {
+        let response = jsonrpc::process_jsonrpc::<TestIndexer>(
+            &rpc,
+            &trees,
+            request,
+            &sub_tx,
+            &sub_response_tx,
+            &mut subs,
+        )
+        .await;
+        response.map(|response| serde_json::from_str::<serde_json::Value>(&response).unwrap())
+    };
+
+    let response =
+        request(serde_json::json!({"jsonrpc": "2.0", "method": "index_sizeOnDisk", "id": 1}))
+            .await
+            .unwrap();
+    assert_eq!(response["id"], serde_json::json!(1));
+    assert!(response["result"].is_u64());
+    // Empty batches are invalid requests.
+    let response = request(serde_json::json!([])).await.unwrap();
+    assert_eq!(response["error"]["code"], serde_json::json!(-32600));
+    assert_eq!(response["id"], serde_json::Value::Null);
+    // Notifications don't get a response.
+    let notification = serde_json::json!({"jsonrpc": "2.0", "method": "index_sizeOnDisk"});
+    assert_eq!(request(notification.clone()).await, None);
+    assert_eq!(request(serde_json::json!([notification])).await, None);
+}
+
+#[test]
+fn test_http_query_params() {
+    let query =
+        "key=%7B%22type%22%3A%22Variant%22%2C%22value%22%3A%5B0%2C1%5D%7D&limit=5&order=asc";
+    let params = http::query_params(Some(query));
+    assert_eq!(params["limit"], serde_json::json!(5));
+    assert_eq!(params["order"], serde_json::json!("asc"));
+
+    let msg = request_from_params::<ChainKey>("GetEvents", params).unwrap();
+    let RequestMessage::GetEvents { key, query } = msg else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(key, Key::Variant(0, 1));
+    assert_eq!(query.limit, Some(5));
+    assert!(matches!(query.order, Order::Asc));
+
+    let params = http::query_params(None);
+    assert!(request_from_params::<ChainKey>("GetEvents", params).is_err());
+}
+
+#[tokio::test]
+async fn test_process_http_request() {
+    use hyper::{Method, StatusCode};
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let rpc = offline_rpc();
+    indexer.index_event(Key::Variant(0, 1), 7, 2).unwrap();
+    let value = SpanDbValue {
+        start: 2_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(9_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let request = |method: Method, path: &'static str, query: Option<&'static str>| {
+        let rpc = rpc.clone();
+        let trees = trees.clone();
+        async move {
+            http::process_http_request::<TestIndexer>(&rpc, &trees, &method, path, query).await
+        }
+    };
+
+    let Some((StatusCode::OK, ResponseMessage::Status(spans))) =
+        request(Method::GET, "/status", None).await
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(spans, vec![Span { start: 2, end: 9 }]);
+    let query = "key=%7B%22type%22%3A%22Variant%22%2C%22value%22%3A%5B0%2C1%5D%7D";
+    let Some((StatusCode::OK, ResponseMessage::Events { events, .. })) =
+        request(Method::GET, "/events", Some(query)).await
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 1);
+    let Some((StatusCode::OK, ResponseMessage::TreeStats(_))) =
+        request(Method::GET, "/tree-stats", None).await
+    else {
+        panic!("Wrong response message.");
+    };
+    // Invalid requests are client errors.
+    let Some((StatusCode::BAD_REQUEST, ResponseMessage::Error { code, .. })) =
+        request(Method::GET, "/events", Some("limit=5")).await
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::InvalidRequest);
+    // Failures of the indexer are server errors.
+    let Some((StatusCode::INTERNAL_SERVER_ERROR, ResponseMessage::Error { code, .. })) =
+        request(Method::GET, "/variants", None).await
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::Connection);
+    // Unknown routes and methods, and admin routes, are not found.
+    assert!(request(Method::GET, "/unknown", None).await.is_none());
+    assert!(request(Method::POST, "/status", None).await.is_none());
+    assert!(request(Method::POST, "/reindex", Some("start=2&end=4"))
+        .await
+        .is_none());
+    let response = http::hyper_response::<ChainKey>(None);
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = http::hyper_response(request(Method::GET, "/status", None).await);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[hyper::header::CONTENT_TYPE],
+        "application/json"
+    );
+}
+
+#[test]
+fn test_process_admin_request() {
+    use hyper::{Method, StatusCode};
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let value = SpanDbValue {
+        start: 2_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(9_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let request = |method: Method, path: &str, query: Option<&str>| {
+        http::process_admin_request::<TestIndexer>(&trees, &sub_tx, &method, path, query)
+    };
+
+    let Some((StatusCode::OK, ResponseMessage::Reindexing { start, end })) =
+        request(Method::POST, "/reindex", Some("start=1&end=4"))
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!((start, end), (2, 4));
+    let Ok(SubscriptionMessage::ReindexBlocks { start: 2, end: 4 }) = sub_rx.try_recv() else {
+        panic!("Wrong subscription message.");
+    };
+    // Missing parameters and empty ranges are client errors.
+    for query in [None, Some("start=2"), Some("start=10&end=12")] {
+        let Some((StatusCode::BAD_REQUEST, ResponseMessage::Error { .. })) =
+            request(Method::POST, "/reindex", query)
+        else {
+            panic!("Wrong response message.");
+        };
+    }
+    assert!(matches!(sub_rx.try_recv(), Err(TryRecvError::Empty)));
+    // Only re-index requests are served on the admin port.
+    assert!(request(Method::GET, "/reindex", Some("start=2&end=4")).is_none());
+    assert!(request(Method::GET, "/status", None).is_none());
+}
+
+#[tokio::test]
+async fn test_process_msg_unsubscribe_id() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Variant(3, 65);
+
+    for id in [1, 2] {
+        process_msg_subscribe_events::<TestIndexer>(
+            key.clone(),
+            BlockRange::default(),
+            Some(RequestId::Number(id)),
+            &sub_tx,
+            &sub_response_tx,
+        );
+        let msg = sub_rx.recv().await.unwrap();
+        process_sub_msg(&indexer, msg);
+    }
+
+    process_msg_unsubscribe_events::<TestIndexer>(
+        key.clone(),
+        Some(RequestId::Number(1)),
+        &sub_tx,
+        &sub_response_tx,
+    );
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
+
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+
+    let response = sub_response_rx.recv().await.unwrap();
+    assert_eq!(response.id, Some(RequestId::Number(2)));
+    let Err(TryRecvError::Empty) = sub_response_rx.try_recv() else {
+        panic!("Wrong response message.");
+    };
+}
+
+#[tokio::test]
+async fn test_process_msg_subscribe_events() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Variant(3, 65);
+
+    let response = process_msg_subscribe_events::<TestIndexer>(
+        key.clone(),
+        BlockRange::default(),
+        None,
+        &sub_tx,
+        &sub_response_tx,
+    );
+
+    let ResponseMessage::Subscribed = response else {
+        panic!("Wrong response message.");
+    };
+
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
+
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+
+    let response_msg = sub_response_rx.recv().await.unwrap().msg;
+    let ResponseMessage::Events {
+        key: response_key,
+        events,
+        ..
+    } = response_msg
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 4);
+
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+
+    let response_msg = sub_response_rx.recv().await.unwrap().msg;
+    let ResponseMessage::Events {
+        key: response_key,
+        events,
+        ..
+    } = response_msg
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 8);
+
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+
+    let response_msg = sub_response_rx.recv().await.unwrap().msg;
+    let ResponseMessage::Events {
+        key: response_key,
+        events,
+        ..
+    } = response_msg
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 10);
+
+    let response =
+        process_msg_unsubscribe_events::<TestIndexer>(key.clone(), None, &sub_tx, &sub_response_tx);
+
+    let ResponseMessage::Unsubscribed = response else {
+        panic!("Wrong response message.");
+    };
+
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
 
-    let msg = sub_rx.recv().await.unwrap();
-    process_sub_msg(&indexer, msg);
-
     indexer.index_event(key.clone(), 18, 5).unwrap();
 
     let response_msg = sub_response_rx.try_recv();
@@ -847,10 +2194,52 @@ async fn test_process_msg_subscribe_events() {
     };
 }
 
+#[tokio::test]
+async fn test_process_msg_subscribe_events_range() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Variant(3, 65);
+    let range = BlockRange {
+        from_block: Some(5),
+        to_block: Some(9),
+    };
+
+    process_msg_subscribe_events::<TestIndexer>(
+        key.clone(),
+        range,
+        Some(RequestId::Number(7)),
+        &sub_tx,
+        &sub_response_tx,
+    );
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
+
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+
+    let response = sub_response_rx.recv().await.unwrap();
+    assert_eq!(response.id, Some(RequestId::Number(7)));
+    let ResponseMessage::Events { events, .. } = response.msg else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 8);
+
+    let response_msg = sub_response_rx.try_recv();
+
+    let Err(TryRecvError::Empty) = response_msg else {
+        panic!("Wrong response message.");
+    };
+}
+
 #[test]
 fn test_load_spans() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     trees.span.clear().unwrap();
     let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
     assert_eq!(trees.span.len(), 0);
@@ -1025,17 +2414,568 @@ fn test_load_spans() {
     );
 }
 
+#[test]
+fn test_prune_trees() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    let chain_key = Key::Chain(ChainKey::TestIndex(3));
+    for block_number in [4, 8, 10] {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+        indexer
+            .index_event(chain_key.clone(), block_number, 1)
+            .unwrap();
+        indexer.store_payload(block_number, 5, 100, &[0]).unwrap();
+        trees
+            .block_hash
+            .insert(block_number.to_be_bytes(), [0; 32])
+            .unwrap();
+    }
+    for (start, end) in [(1_u32, 4_u32), (6, 9), (10, 12)] {
+        let value = SpanDbValue {
+            start: start.into(),
+            version: 0_u16.into(),
+            index_variant: 0,
+        };
+        trees
+            .span
+            .insert(end.to_be_bytes(), value.as_bytes())
+            .unwrap();
+    }
+    let mut spans = vec![
+        Span { start: 1, end: 4 },
+        Span { start: 6, end: 9 },
+        Span { start: 10, end: 12 },
+    ];
+    prune_trees::<TestIndexer>(&trees, &mut spans, 8).unwrap();
+
+    assert_eq!(
+        spans,
+        vec![Span { start: 8, end: 9 }, Span { start: 10, end: 12 }]
+    );
+    assert_eq!(trees.span.len(), 2);
+    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
+    assert_eq!(spans[0], Span { start: 8, end: 9 });
+
+    for key in [key, chain_key] {
+        let response =
+            process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default())
+                .unwrap();
+        let ResponseMessage::Events { events, .. } = response else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.block_number >= 8));
+        let response =
+            process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+        let ResponseMessage::EventCount { count, .. } = response else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(count, 2);
+    }
+    assert_eq!(trees.payload.len(), 2);
+    assert_eq!(trees.block_hash.len(), 2);
+    assert!(!trees.block_hash.contains_key(4_u32.to_be_bytes()).unwrap());
+
+    // Pruning everything removes the counts.
+    let mut spans = vec![];
+    prune_trees::<TestIndexer>(&trees, &mut spans, 20).unwrap();
+    assert_eq!(trees.count.len(), 0);
+    assert_eq!(trees.span.len(), 0);
+    assert_eq!(trees.payload.len(), 0);
+}
+
+#[test]
+fn test_prune_chunks() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    let block_count: u32 = (REMOVE_CHUNK_SIZE + 10).try_into().unwrap();
+    for block_number in 0..block_count {
+        indexer.index_event(key.clone(), block_number, 0).unwrap();
+    }
+    let tree = &trees.substrate.account_index;
+    // Scans stop after a chunk of keys.
+    let (keys, next) = scan_keys(tree, Bound::Unbounded, Bound::Unbounded, |key| {
+        event_key_block_number(key).unwrap() < 5
+    })
+    .unwrap();
+    assert_eq!(keys.len(), 5);
+    let next = next.unwrap();
+    assert_eq!(
+        event_key_block_number(&next).unwrap(),
+        u32::try_from(REMOVE_CHUNK_SIZE).unwrap() - 1
+    );
+    let (keys, next) = scan_keys(tree, Bound::Excluded(&next), Bound::Unbounded, |_| true).unwrap();
+    assert_eq!(keys.len(), 10);
+    assert_eq!(next, None);
+
+    let mut spans = vec![Span {
+        start: 0,
+        end: block_count - 1,
+    }];
+    prune_trees::<TestIndexer>(&trees, &mut spans, block_count - 2).unwrap();
+    assert_eq!(tree.len(), 2);
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn test_reindex_blocks() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    let chain_key = Key::Chain(ChainKey::TestIndex(3));
+    for block_number in [4, 8, 10] {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+        indexer
+            .index_event(chain_key.clone(), block_number, 1)
+            .unwrap();
+        indexer.store_payload(block_number, 5, 100, &[0]).unwrap();
+        trees
+            .block_hash
+            .insert(block_number.to_be_bytes(), [0; 32])
+            .unwrap();
+    }
+    for (start, end) in [(1_u32, 4_u32), (6, 12), (14, 16)] {
+        let value = SpanDbValue {
+            start: start.into(),
+            version: 0_u16.into(),
+            index_variant: 0,
+        };
+        trees
+            .span
+            .insert(end.to_be_bytes(), value.as_bytes())
+            .unwrap();
+    }
+    reindex_blocks::<TestIndexer>(&trees, 8, 9).unwrap();
+
+    // The spans are kept and the blocks are retried as failed blocks.
+    assert_eq!(trees.span.len(), 3);
+    let response = process_msg_failed_blocks::<TestIndexer>(&trees).unwrap();
+    let ResponseMessage::FailedBlocks(failed_blocks) = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(
+        failed_blocks
+            .iter()
+            .map(|failed_block| failed_block.block_number)
+            .collect::<Vec<_>>(),
+        vec![8, 9]
+    );
+    for key in [key, chain_key] {
+        let response =
+            process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default())
+                .unwrap();
+        let ResponseMessage::Events { events, .. } = response else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.block_number != 8));
+        let response =
+            process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+        let ResponseMessage::EventCount { count, .. } = response else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(count, 2);
+    }
+    assert_eq!(trees.payload.len(), 2);
+    assert!(!trees.block_hash.contains_key(8_u32.to_be_bytes()).unwrap());
+    assert!(trees.block_hash.contains_key(10_u32.to_be_bytes()).unwrap());
+
+    // Running indexers are asked to re-index blocks.
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let response = process_msg_reindex_blocks::<TestIndexer>(&trees, 8, 9, &sub_tx).unwrap();
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        r#"{"type":"reindexing","data":{"start":8,"end":9}}"#
+    );
+    let Ok(SubscriptionMessage::ReindexBlocks { start: 8, end: 9 }) = sub_rx.try_recv() else {
+        panic!("Wrong subscription message.");
+    };
+    // The range is clipped to the indexed blocks.
+    let response = process_msg_reindex_blocks::<TestIndexer>(&trees, 0, u32::MAX, &sub_tx).unwrap();
+    let ResponseMessage::Reindexing { start: 1, end: 16 } = response else {
+        panic!("Wrong response message.");
+    };
+    let Ok(SubscriptionMessage::ReindexBlocks { start: 1, end: 16 }) = sub_rx.try_recv() else {
+        panic!("Wrong subscription message.");
+    };
+    // Invalid and oversized ranges are rejected.
+    let value = SpanDbValue {
+        start: 20_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(1_000_000_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    for (start, end) in [
+        (9, 8),
+        (2_000_000, 3_000_000),
+        (20, REINDEX_MAX_BLOCKS + 20),
+    ] {
+        assert!(matches!(
+            process_msg_reindex_blocks::<TestIndexer>(&trees, start, end, &sub_tx),
+            Err(IndexError::InvalidRequest)
+        ));
+    }
+    process_msg_reindex_blocks::<TestIndexer>(&trees, 20, REINDEX_MAX_BLOCKS + 19, &sub_tx)
+        .unwrap();
+    assert!(sub_rx.try_recv().is_ok());
+    assert!(sub_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_best_route() {
+    use std::collections::HashMap;
+    // Hashes are the block number, plus 100 for blocks on a fork.
+    let indexed = HashMap::from([(5, 5), (6, 6), (7, 7)]);
+    let is_indexed = |block_number: u32, hash: &u32| indexed.get(&block_number) == Some(hash);
+    let parents = HashMap::from([(108, 107), (107, 106), (106, 5), (9, 8), (8, 7)]);
+    let get_parent = |block_number: u32, hash: u32| {
+        let parent = parents
+            .get(&hash)
+            .copied()
+            .ok_or(IndexError::BlockNotFound(block_number));
+        async move { parent }
+    };
+    // The next block of the best chain.
+    let route = best_route(4, (8, 8), 7, is_indexed, get_parent)
+        .await
+        .unwrap();
+    assert_eq!(route, (7, vec![(8, 8)]));
+    // Blocks missed since the last best block.
+    let route = best_route(4, (9, 9), 8, is_indexed, get_parent)
+        .await
+        .unwrap();
+    assert_eq!(route, (7, vec![(8, 8), (9, 9)]));
+    // A reorg forks from the last block both chains have in common.
+    let route = best_route(4, (9, 109), 108, is_indexed, get_parent)
+        .await
+        .unwrap();
+    assert_eq!(route, (5, vec![(6, 106), (7, 107), (8, 108), (9, 109)]));
+    // The walk stops at the last finalized block.
+    let route = best_route(6, (8, 108), 107, is_indexed, get_parent)
+        .await
+        .unwrap();
+    assert_eq!(route, (6, vec![(7, 107), (8, 108)]));
+    // A best block that has already been indexed has no route.
+    let route = best_route(4, (7, 7), 6, is_indexed, get_parent)
+        .await
+        .unwrap();
+    assert_eq!(route, (7, vec![]));
+    // Missing headers are reported.
+    let Err(IndexError::BlockNotFound(8)) =
+        best_route(4, (9, 209), 208, is_indexed, get_parent).await
+    else {
+        panic!("Found missing block.");
+    };
+}
+
+#[test]
+fn test_rollback_unfinalized() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let key = Key::<ChainKey>::Substrate(SubstrateKey::AccountIndex(3));
+    let chain_key = Key::<ChainKey>::Chain(ChainKey::TestIndex(3));
+    // Block 4 is finalized, blocks 8 and 10 are not.
+    for block_number in [4_u32, 8, 10] {
+        let mut batch = IndexBatch::default();
+        key.write_db_key(&trees, &mut batch, block_number, 5)
+            .unwrap();
+        key.write_db_key(&trees, &mut batch, block_number, 5)
+            .unwrap();
+        chain_key
+            .write_db_key(&trees, &mut batch, block_number, 1)
+            .unwrap();
+        batch
+            .writes
+            .insert(&trees.block_hash, block_number.to_be_bytes(), [0; 32]);
+        let payload_key = EventKey {
+            block_number: block_number.into(),
+            event_index: 5.into(),
+        };
+        batch
+            .writes
+            .insert(&trees.payload, payload_key.as_bytes(), [0]);
+        let keys = commit_batch_with(&trees, batch, |keys, writes| {
+            assert_eq!(keys.len(), 2);
+            if block_number != 4 {
+                let value = encode_event_keys(keys);
+                let decoded = decode_event_keys(&trees.index_trees(), &value).unwrap();
+                assert!(decoded
+                    .iter()
+                    .zip(keys)
+                    .all(|((tree, key), (other_tree, other_key))| {
+                        tree.name() == other_tree.name() && key == other_key
+                    }));
+                writes.insert(&trees.unfinalized, block_number.to_be_bytes(), value);
+            }
+        })
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+    }
+    rollback_unfinalized(&trees).unwrap();
+
+    assert!(trees.unfinalized.is_empty());
+    for key in [key, chain_key] {
+        let response =
+            process_msg_get_events::<TestIndexer>(&trees, key.clone(), EventQuery::default())
+                .unwrap();
+        let ResponseMessage::Events { events, .. } = response else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].block_number, 4);
+        let response =
+            process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+        let ResponseMessage::EventCount { count, .. } = response else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(count, 1);
+    }
+    assert_eq!(trees.payload.len(), 1);
+    assert_eq!(trees.block_hash.len(), 1);
+    assert!(trees.block_hash.contains_key(4_u32.to_be_bytes()).unwrap());
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::snapshot::*;
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    let chain_key = Key::Chain(ChainKey::TestIndex(3));
+    for block_number in [4, 8] {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+        indexer
+            .index_event(chain_key.clone(), block_number, 1)
+            .unwrap();
+        indexer.store_payload(block_number, 5, 100, &[0]).unwrap();
+    }
+    let value = SpanDbValue {
+        start: 2_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(9_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("snapshot-{}.tar.gz", std::process::id()));
+    let manifest = export_snapshot::<TestIndexer>(&trees, &path).unwrap();
+    assert_eq!(manifest.spans, vec![Span { start: 2, end: 9 }]);
+    assert_eq!(manifest.schema_version, migration::SCHEMA_VERSION);
+
+    let imported = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    assert_eq!(
+        import_snapshot::<TestIndexer>(&imported, &path).unwrap(),
+        manifest
+    );
+    assert_eq!(
+        imported.root.get("genesis_hash").unwrap(),
+        Some(TestIndexer::get_genesis_hash().as_ref().to_vec())
+    );
+    for (tree, imported_tree) in trees.all_trees().into_iter().zip(imported.all_trees()) {
+        assert_eq!(
+            tree.iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            imported_tree.iter().collect::<Result<Vec<_>, _>>().unwrap()
+        );
+    }
+    let response =
+        process_msg_count_events::<TestIndexer>(&imported, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 2);
+    // Snapshots can only be imported into an empty database.
+    let Err(SnapshotError::NotEmpty) = import_snapshot::<TestIndexer>(&imported, &path) else {
+        panic!("Imported into non-empty database.");
+    };
+    assert!(!path.with_extension("spool").exists());
+
+    // The spool file is removed when the export fails.
+    let dir_path = std::env::temp_dir().join(format!("snapshot-dir-{}", std::process::id()));
+    std::fs::create_dir(&dir_path).unwrap();
+    let Err(SnapshotError::Io(_)) = export_snapshot::<TestIndexer>(&trees, &dir_path) else {
+        panic!("Exported to a directory.");
+    };
+    assert!(!dir_path.with_extension("spool").exists());
+    std::fs::remove_dir(dir_path).unwrap();
+
+    let write_archive = |manifest: &Manifest, entries: Vec<(&str, Vec<u8>)>| {
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            std::fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let data = serde_json::to_vec(manifest).unwrap();
+        for (entry_path, data) in [("manifest.json", data)].into_iter().chain(entries) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len().try_into().unwrap());
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, entry_path, data.as_slice())
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    };
+    // Length prefixes running past the end of a tree entry are rejected.
+    let mut record = u32::MAX.to_be_bytes().to_vec();
+    record.extend_from_slice(&[0; 4]);
+    write_archive(&manifest, vec![("trees/account_index", record)]);
+    let corrupt = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let Err(SnapshotError::Corrupt) = import_snapshot::<TestIndexer>(&corrupt, &path) else {
+        panic!("Imported corrupt snapshot.");
+    };
+    // Manifests are checked before anything is imported.
+    let span_record = [9_u32.to_be_bytes().to_vec(), value.as_bytes().to_vec()]
+        .iter()
+        .flat_map(|bytes| {
+            [
+                u32::try_from(bytes.len()).unwrap().to_be_bytes().to_vec(),
+                bytes.clone(),
+            ]
+        })
+        .flatten()
+        .collect::<Vec<u8>>();
+    let future = Manifest {
+        schema_version: migration::SCHEMA_VERSION + 1,
+        ..manifest.clone()
+    };
+    write_archive(&future, vec![("trees/span", span_record.clone())]);
+    let Err(SnapshotError::IncompatibleSchema(_)) = import_snapshot::<TestIndexer>(&corrupt, &path)
+    else {
+        panic!("Imported snapshot with a newer schema.");
+    };
+    for spans in [
+        vec![Span { start: 9, end: 2 }],
+        vec![Span { start: 6, end: 9 }, Span { start: 2, end: 4 }],
+        vec![Span { start: 2, end: 6 }, Span { start: 6, end: 9 }],
+    ] {
+        let unordered = Manifest {
+            spans,
+            ..manifest.clone()
+        };
+        write_archive(&unordered, vec![("trees/span", span_record.clone())]);
+        let Err(SnapshotError::InvalidSpans) = import_snapshot::<TestIndexer>(&corrupt, &path)
+        else {
+            panic!("Imported snapshot with invalid spans.");
+        };
+        assert!(corrupt.span.is_empty());
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_fsck() {
+    use crate::fsck::*;
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::AccountIndex(3));
+    for block_number in [2, 5, 10] {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+        indexer.store_payload(block_number, 5, 100, &[0]).unwrap();
+    }
+    indexer
+        .index_event(Key::Chain(ChainKey::TestIndex(3)), 12, 1)
+        .unwrap();
+    // Unfinalized blocks are not in any span, but are not orphans.
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.store_payload(8, 5, 100, &[0]).unwrap();
+    trees.unfinalized.insert(8_u32.to_be_bytes(), []).unwrap();
+    for (start, end) in [(1_u32, 4_u32), (3, 6)] {
+        let value = SpanDbValue {
+            start: start.into(),
+            version: 0_u16.into(),
+            index_variant: 0,
+        };
+        trees
+            .span
+            .insert(end.to_be_bytes(), value.as_bytes())
+            .unwrap();
+    }
+    trees.span.insert([0, 0, 9], [0]).unwrap();
+    trees.count.insert(b"era_index\0\0", [0]).unwrap();
+
+    let options = FsckOptions::default();
+    let findings = fsck::fsck::<TestIndexer>(&trees, None, &options)
+        .await
+        .unwrap();
+    assert_eq!(
+        findings,
+        vec![
+            Finding::CorruptSpan {
+                key: "0x000009".into()
+            },
+            Finding::OverlappingSpans {
+                first: Span { start: 1, end: 4 },
+                second: Span { start: 3, end: 6 },
+            },
+            Finding::OrphanedKeys {
+                tree: "account_index".into(),
+                keys: 1,
+                first_block: 10,
+                last_block: 10,
+            },
+            Finding::OrphanedKeys {
+                tree: "test_index".into(),
+                keys: 1,
+                first_block: 12,
+                last_block: 12,
+            },
+            Finding::CountMismatch { keys: 1 },
+            Finding::OrphanedKeys {
+                tree: "payload".into(),
+                keys: 1,
+                first_block: 10,
+                last_block: 10,
+            },
+        ]
+    );
+    // Checking does not change the database.
+    assert_eq!(trees.span.len(), 3);
+
+    let options = FsckOptions {
+        sample: 0,
+        repair: true,
+    };
+    fsck::fsck::<TestIndexer>(&trees, None, &options)
+        .await
+        .unwrap();
+    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
+    assert_eq!(spans, vec![Span { start: 1, end: 6 }]);
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key, BlockRange::default()).unwrap();
+    let ResponseMessage::EventCount { count, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(count, 3);
+    assert_eq!(trees.payload.len(), 3);
+    let findings = fsck::fsck::<TestIndexer>(&trees, None, &FsckOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(findings, vec![]);
+}
+
 #[test]
 fn test_check_span() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
     trees.span.clear().unwrap();
     let mut spans = Vec::new();
     let mut span = Span {
         start: 100,
         end: 120,
     };
-    check_span(&trees.span, &mut spans, &mut span).unwrap();
+    let mut writes = DbBatch::default();
+    check_span(&trees.span, &mut writes, &mut spans, &mut span);
+    trees.root.commit(writes).unwrap();
     assert_eq!(trees.span.len(), 0);
     assert_eq!(spans.len(), 0);
     assert_eq!(
@@ -1055,7 +2995,9 @@ fn test_check_span() {
         .insert(20_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
     spans.push(Span { start: 10, end: 20 });
-    check_span(&trees.span, &mut spans, &mut span).unwrap();
+    let mut writes = DbBatch::default();
+    check_span(&trees.span, &mut writes, &mut spans, &mut span);
+    trees.root.commit(writes).unwrap();
     assert_eq!(trees.span.len(), 1);
     assert_eq!(spans.len(), 1);
     assert_eq!(spans[0], Span { start: 10, end: 20 });
@@ -1076,7 +3018,11 @@ fn test_check_span() {
         .insert(99_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
     spans.push(Span { start: 30, end: 99 });
-    check_span(&trees.span, &mut spans, &mut span).unwrap();
+    let mut writes = DbBatch::default();
+    check_span(&trees.span, &mut writes, &mut spans, &mut span);
+    // The merged span is removed when the writes are committed.
+    assert_eq!(trees.span.len(), 2);
+    trees.root.commit(writes).unwrap();
     assert_eq!(trees.span.len(), 1);
     assert_eq!(spans.len(), 1);
     assert_eq!(spans[0], Span { start: 10, end: 20 });
@@ -1094,14 +3040,174 @@ fn test_check_next_batch_block() {
     let mut spans = Vec::new();
     let mut next_batch_block = 50;
 
-    check_next_batch_block(&spans, &mut next_batch_block);
+    let target = BlockRange::default();
+    assert!(check_next_batch_block(
+        &spans,
+        &mut next_batch_block,
+        &target
+    ));
     assert_eq!(next_batch_block, 50);
     spans.push(Span { start: 20, end: 30 });
-    check_next_batch_block(&spans, &mut next_batch_block);
+    assert!(check_next_batch_block(
+        &spans,
+        &mut next_batch_block,
+        &target
+    ));
     assert_eq!(next_batch_block, 50);
     spans.push(Span { start: 45, end: 50 });
-    check_next_batch_block(&spans, &mut next_batch_block);
+    assert!(check_next_batch_block(
+        &spans,
+        &mut next_batch_block,
+        &target
+    ));
     assert_eq!(next_batch_block, 44);
+
+    // Blocks after the end of the target range are skipped.
+    let target = BlockRange {
+        from_block: Some(25),
+        to_block: Some(40),
+    };
+    let mut next_batch_block = 50;
+    assert!(check_next_batch_block(
+        &spans,
+        &mut next_batch_block,
+        &target
+    ));
+    assert_eq!(next_batch_block, 40);
+    // There are no more blocks to index after the start of the target range.
+    let mut next_batch_block = 30;
+    assert!(!check_next_batch_block(
+        &spans,
+        &mut next_batch_block,
+        &target
+    ));
+    assert_eq!(next_batch_block, 19);
+    let mut next_batch_block = 24;
+    assert!(!check_next_batch_block(
+        &spans,
+        &mut next_batch_block,
+        &target
+    ));
+}
+
+#[test]
+fn test_reconnect_delay() {
+    let failed = Duration::from_secs(5);
+    // The delay doubles while connections fail quickly.
+    let mut delay = Duration::ZERO;
+    let mut schedule = vec![];
+    for _ in 0..8 {
+        delay = reconnect_delay(delay, failed);
+        schedule.push(delay.as_secs());
+    }
+    assert_eq!(schedule, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    // The delay is carried across connections that fail quickly.
+    assert_eq!(
+        reconnect_delay(Duration::from_secs(8), Duration::ZERO),
+        Duration::from_secs(16)
+    );
+    // The delay is reset after a healthy connection.
+    assert_eq!(
+        reconnect_delay(MAX_RECONNECT_DELAY, HEALTHY_CONNECTION),
+        RECONNECT_DELAY
+    );
+    assert_eq!(
+        reconnect_delay(
+            MAX_RECONNECT_DELAY,
+            HEALTHY_CONNECTION - Duration::from_secs(1)
+        ),
+        MAX_RECONNECT_DELAY
+    );
+
+    // The indexing loop resets the delay after a healthy connection.
+    let mut backoff = Backoff::default();
+    let start = tokio::time::Instant::now();
+    for _ in 0..8 {
+        backoff.disconnected(start);
+    }
+    assert_eq!(backoff.delay(), MAX_RECONNECT_DELAY);
+    backoff.connected(start);
+    backoff.disconnected(start + HEALTHY_CONNECTION - Duration::from_secs(1));
+    assert_eq!(backoff.delay(), MAX_RECONNECT_DELAY);
+    backoff.connected(start);
+    backoff.disconnected(start + HEALTHY_CONNECTION);
+    assert_eq!(backoff.delay(), RECONNECT_DELAY);
+    // Failed connection attempts aren't counted as connected since the last connection.
+    backoff.disconnected(start + HEALTHY_CONNECTION * 2);
+    assert_eq!(backoff.delay(), RECONNECT_DELAY * 2);
+}
+
+#[test]
+fn test_endpoints() {
+    // Endpoints are identified by their client.
+    let mut endpoints = Endpoints::new(vec![
+        Endpoint::new("a".to_owned(), Some(0)),
+        Endpoint::new("b".to_owned(), Some(1)),
+        Endpoint::new("c".to_owned(), None),
+    ]);
+    // Batch indexing rotates through the healthy endpoints.
+    let batch: Vec<_> = (0..4).map(|_| endpoints.batch_client().unwrap()).collect();
+    assert_eq!(batch, vec![(0, 0), (1, 1), (0, 0), (0, 0)]);
+    // Failed endpoints are taken out of rotation.
+    endpoints.endpoint_failed(0);
+    let batch: Vec<_> = (0..3).map(|_| endpoints.batch_client().unwrap()).collect();
+    assert_eq!(batch, vec![(1, 1), (1, 1), (1, 1)]);
+    // Endpoints that pass a health check are used again.
+    endpoints
+        .checked(vec![
+            (0, Ok((0, 100))),
+            (1, Ok((1, 102))),
+            (2, Err(IndexError::Disconnected)),
+        ])
+        .unwrap();
+    // The most up-to-date endpoint follows the head.
+    assert_eq!(endpoints.select_head(), Some((1, 1)));
+    assert!(!endpoints.is_head_lagging());
+    endpoints
+        .checked(vec![(0, Ok((0, 105))), (1, Ok((1, 102)))])
+        .unwrap();
+    assert!(!endpoints.is_head_lagging());
+    endpoints
+        .checked(vec![(0, Ok((0, 106))), (1, Ok((1, 102)))])
+        .unwrap();
+    assert!(endpoints.is_head_lagging());
+    assert_eq!(endpoints.select_head(), Some((0, 0)));
+    assert!(!endpoints.is_head_lagging());
+    // The head lags if its endpoint fails.
+    endpoints.endpoint_failed(0);
+    assert!(endpoints.is_head_lagging());
+    assert_eq!(endpoints.select_head(), Some((1, 1)));
+    // Endpoints on another chain are never used again.
+    endpoints
+        .checked(vec![(1, Err(IndexError::WrongGenesisHash))])
+        .unwrap();
+    assert_eq!(endpoints.select_head(), None);
+    assert_eq!(endpoints.batch_client(), None);
+    endpoints
+        .checked(vec![(0, Ok((0, 110))), (2, Ok((2, 110)))])
+        .unwrap();
+    assert_eq!(endpoints.select_head(), Some((0, 0)));
+    let batch: Vec<_> = (0..3).map(|_| endpoints.batch_client().unwrap()).collect();
+    assert_eq!(batch, vec![(2, 2), (0, 0), (2, 2)]);
+    // Indexing fails once every endpoint is on another chain.
+    endpoints
+        .checked(vec![(0, Err(IndexError::WrongGenesisHash))])
+        .unwrap();
+    assert!(matches!(
+        endpoints.checked(vec![(2, Err(IndexError::WrongGenesisHash))]),
+        Err(IndexError::WrongGenesisHash)
+    ));
+}
+
+#[tokio::test]
+async fn test_no_endpoints() {
+    let db_config = sled::Config::new().temporary(true);
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees);
+    assert!(matches!(
+        indexer.index_block(1).await,
+        Err(IndexError::Disconnected)
+    ));
 }
 
 // Tests for Ideal Network extensions
@@ -1109,14 +3215,17 @@ fn test_check_next_batch_block() {
 #[test]
 fn test_subscription_id_key() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
 
     // Create a subscription ID key - use a simple pattern for testing
     let test_bytes = [123u8; 32];
     let key = SubstrateKey::SubscriptionId(SubscriptionId::from(test_bytes));
 
     // Write it to the database
-    key.write_db_key(&trees.substrate, 100, 2).unwrap();
+    let mut batch = IndexBatch::default();
+    key.write_db_key(&trees.substrate, &mut batch, 100, 2)
+        .unwrap();
+    commit_batch(&trees, batch).unwrap();
 
     // Check that the key was written correctly
     let mut iter = trees.substrate.subscription_id.iter();
@@ -1141,15 +3250,17 @@ fn test_subscription_id_key() {
 #[test]
 fn test_idn_key_storage() {
     let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::open_sled(db_config).unwrap()).unwrap();
 
     // Create test data for the subscription key type
     let subscription_id = SubstrateKey::SubscriptionId(SubscriptionId::from([55 as u8; 32]));
 
     // Write events to the database
+    let mut batch = IndexBatch::default();
     subscription_id
-        .write_db_key(&trees.substrate, 700, 7)
+        .write_db_key(&trees.substrate, &mut batch, 700, 7)
         .unwrap();
+    commit_batch(&trees, batch).unwrap();
 
     // Verify count
     assert_eq!(trees.substrate.subscription_id.len(), 1);
@@ -1,34 +1,80 @@
-use crate::shared::*;
+use crate::{
+    jsonrpc::*,
+    query::query_events,
+    shared::*,
+    storage::{StorageError, Tree},
+    substrate::REINDEX_MAX_BLOCKS,
+};
 use futures::{SinkExt, StreamExt};
-use sled::Tree;
-use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{cell::Cell, net::SocketAddr, ops::Bound};
 use subxt::backend::legacy::LegacyRpcMethods;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedSender},
     watch::Receiver,
 };
-use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::{
+    self,
+    handshake::server::{Callback, ErrorResponse, Request as WsRequest, Response as WsResponse},
+    http::header::SEC_WEBSOCKET_PROTOCOL,
+};
 use tracing::{error, info};
-use zerocopy::FromBytes;
+use zerocopy::{AsBytes, FromBytes};
 
-pub fn process_msg_status<R: RuntimeIndexer>(span_db: &Tree) -> ResponseMessage<R::ChainKey> {
+/// Reports the indexed spans. Failed blocks don't stop the spans from growing as they are retried
+/// later, so they are left out of the spans until they have been indexed.
+pub fn process_msg_status<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+) -> ResponseMessage<R::ChainKey> {
+    let failed: Vec<u32> = trees
+        .failed
+        .iter()
+        .keys()
+        .flatten()
+        .map(|key| u32::from_be_bytes(key.as_slice().try_into().unwrap()))
+        .collect();
     let mut spans = vec![];
-    for (key, value) in span_db.into_iter().flatten() {
+    for (key, value) in trees.span.into_iter().flatten() {
         let span_value = SpanDbValue::read_from(&value).unwrap();
-        let start: u32 = span_value.start.into();
-        let end: u32 = u32::from_be_bytes(key.as_ref().try_into().unwrap());
-        let span = Span { start, end };
-        spans.push(span);
+        let mut start: u32 = span_value.start.into();
+        let end: u32 = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+        let first = failed.partition_point(|block_number| *block_number < start);
+        for block_number in failed[first..]
+            .iter()
+            .take_while(|block_number| **block_number <= end)
+        {
+            if *block_number > start {
+                spans.push(Span {
+                    start,
+                    end: block_number - 1,
+                });
+            }
+            start = block_number + 1;
+        }
+        if start <= end {
+            spans.push(Span { start, end });
+        }
     }
     ResponseMessage::Status(spans)
 }
 
+pub fn process_msg_target<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    Ok(ResponseMessage::Target(BlockRange::load_target(
+        &trees.root,
+    )?))
+}
+
 pub fn process_msg_subscribe_status<R: RuntimeIndexer>(
+    id: Option<RequestId>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> ResponseMessage<R::ChainKey> {
     let msg = SubscriptionMessage::SubscribeStatus {
+        id,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
@@ -36,10 +82,12 @@ pub fn process_msg_subscribe_status<R: RuntimeIndexer>(
 }
 
 pub fn process_msg_unsubscribe_status<R: RuntimeIndexer>(
+    id: Option<RequestId>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> ResponseMessage<R::ChainKey> {
     let msg = SubscriptionMessage::UnsubscribeStatus {
+        id,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
@@ -72,127 +120,520 @@ pub async fn process_msg_variants<R: RuntimeIndexer>(
     Ok(ResponseMessage::Variants(pallets))
 }
 
-pub fn get_events_variant(tree: &Tree, pallet_id: u8, variant_id: u8) -> Vec<Event> {
+/// Encodes the database key of an event with the given key prefix.
+fn event_db_key(prefix: &[u8], block_number: u32, event_index: u16) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(&block_number.to_be_bytes());
+    key.extend_from_slice(&event_index.to_be_bytes());
+    key
+}
+
+/// Determines the range of database keys to scan for events with the given key prefix. Returns
+/// `None` if the range is inverted, so it contains no events.
+#[allow(clippy::type_complexity)]
+pub fn event_range(prefix: &[u8], query: &EventQuery) -> Option<(Bound<Vec<u8>>, Bound<Vec<u8>>)> {
+    let start = event_db_key(prefix, query.range.from_block.unwrap_or(0), 0);
+    let start = match &query.after {
+        Some(after) => {
+            let after_key = event_db_key(prefix, after.block_number, after.event_index);
+            // Use whichever bound is higher.
+            match after_key >= start {
+                true => Bound::Excluded(after_key),
+                false => Bound::Included(start),
+            }
+        }
+        None => Bound::Included(start),
+    };
+    let end = event_db_key(prefix, query.range.to_block.unwrap_or(u32::MAX), u16::MAX);
+    let end = match &query.before {
+        Some(before) => {
+            let before_key = event_db_key(prefix, before.block_number, before.event_index);
+            // Use whichever bound is lower.
+            match before_key <= end {
+                true => Bound::Excluded(before_key),
+                false => Bound::Included(end),
+            }
+        }
+        None => Bound::Included(end),
+    };
+    let is_empty = match (&start, &end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    };
+    match is_empty {
+        true => None,
+        false => Some((start, end)),
+    }
+}
+
+/// Iterates over the database keys of events with the given key prefix in the requested order.
+pub fn event_keys(
+    tree: &Tree,
+    prefix: &[u8],
+    query: &EventQuery,
+) -> Box<dyn Iterator<Item = Result<Vec<u8>, StorageError>>> {
+    let Some(range) = event_range(prefix, query) else {
+        return Box::new(std::iter::empty());
+    };
+    let iter = tree.range(range).keys();
+    match query.order {
+        Order::Asc => Box::new(iter),
+        Order::Desc => Box::new(iter.rev()),
+    }
+}
+
+pub fn get_events_variant(
+    tree: &Tree,
+    pallet_id: u8,
+    variant_id: u8,
+    query: &EventQuery,
+) -> Result<Vec<Event>, StorageError> {
     let mut events = Vec::new();
-    let mut iter = tree.scan_prefix([pallet_id, variant_id]).keys();
 
-    while let Some(Ok(key)) = iter.next_back() {
-        let key = VariantKey::read_from(&key).unwrap();
+    for key in event_keys(tree, &[pallet_id, variant_id], query) {
+        let key = VariantKey::read_from(&key?).unwrap();
 
         events.push(Event {
             block_number: key.block_number.into(),
             event_index: key.event_index.into(),
+            block_hash: None,
         });
 
-        if events.len() == 100 {
+        if events.len() == query.limit() {
             break;
         }
     }
-    events
+    Ok(events)
 }
 
-pub fn get_events_bytes32(tree: &Tree, key: &Bytes32) -> Vec<Event> {
+pub fn get_events_bytes32(
+    tree: &Tree,
+    key: &Bytes32,
+    query: &EventQuery,
+) -> Result<Vec<Event>, StorageError> {
     let mut events = Vec::new();
-    let mut iter = tree.scan_prefix(key).keys();
 
-    while let Some(Ok(key)) = iter.next_back() {
-        let key = Bytes32Key::read_from(&key).unwrap();
+    for key in event_keys(tree, key.as_ref(), query) {
+        let key = Bytes32Key::read_from(&key?).unwrap();
 
         events.push(Event {
             block_number: key.block_number.into(),
             event_index: key.event_index.into(),
+            block_hash: None,
         });
 
-        if events.len() == 100 {
+        if events.len() == query.limit() {
             break;
         }
     }
-    events
+    Ok(events)
 }
 
-pub fn get_events_u32(tree: &Tree, key: u32) -> Vec<Event> {
+pub fn get_events_u32(
+    tree: &Tree,
+    key: u32,
+    query: &EventQuery,
+) -> Result<Vec<Event>, StorageError> {
     let mut events = Vec::new();
-    let mut iter = tree.scan_prefix(key.to_be_bytes()).keys();
 
-    while let Some(Ok(key)) = iter.next_back() {
-        let key = U32Key::read_from(&key).unwrap();
+    for key in event_keys(tree, &key.to_be_bytes(), query) {
+        let key = U32Key::read_from(&key?).unwrap();
 
         events.push(Event {
             block_number: key.block_number.into(),
             event_index: key.event_index.into(),
+            block_hash: None,
         });
 
-        if events.len() == 100 {
+        if events.len() == query.limit() {
             break;
         }
     }
-    events
+    Ok(events)
 }
 
 pub fn process_msg_get_events_substrate<R: RuntimeIndexer>(
     trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
     key: &SubstrateKey,
-) -> Vec<Event> {
+    query: &EventQuery,
+) -> Result<Vec<Event>, StorageError> {
     match key {
         SubstrateKey::AccountId(account_id) => {
-            get_events_bytes32(&trees.substrate.account_id, account_id)
+            get_events_bytes32(&trees.substrate.account_id, account_id, query)
         }
         SubstrateKey::AccountIndex(account_index) => {
-            get_events_u32(&trees.substrate.account_index, *account_index)
+            get_events_u32(&trees.substrate.account_index, *account_index, query)
         }
         SubstrateKey::BountyIndex(bounty_index) => {
-            get_events_u32(&trees.substrate.bounty_index, *bounty_index)
+            get_events_u32(&trees.substrate.bounty_index, *bounty_index, query)
+        }
+        SubstrateKey::EraIndex(era_index) => {
+            get_events_u32(&trees.substrate.era_index, *era_index, query)
         }
-        SubstrateKey::EraIndex(era_index) => get_events_u32(&trees.substrate.era_index, *era_index),
         SubstrateKey::MessageId(message_id) => {
-            get_events_bytes32(&trees.substrate.message_id, message_id)
+            get_events_bytes32(&trees.substrate.message_id, message_id, query)
         }
-        SubstrateKey::PoolId(pool_id) => get_events_u32(&trees.substrate.pool_id, *pool_id),
+        SubstrateKey::PoolId(pool_id) => get_events_u32(&trees.substrate.pool_id, *pool_id, query),
         SubstrateKey::PreimageHash(preimage_hash) => {
-            get_events_bytes32(&trees.substrate.preimage_hash, preimage_hash)
+            get_events_bytes32(&trees.substrate.preimage_hash, preimage_hash, query)
         }
         SubstrateKey::ProposalHash(proposal_hash) => {
-            get_events_bytes32(&trees.substrate.proposal_hash, proposal_hash)
+            get_events_bytes32(&trees.substrate.proposal_hash, proposal_hash, query)
         }
         SubstrateKey::ProposalIndex(proposal_index) => {
-            get_events_u32(&trees.substrate.proposal_index, *proposal_index)
+            get_events_u32(&trees.substrate.proposal_index, *proposal_index, query)
+        }
+        SubstrateKey::RefIndex(ref_index) => {
+            get_events_u32(&trees.substrate.ref_index, *ref_index, query)
         }
-        SubstrateKey::RefIndex(ref_index) => get_events_u32(&trees.substrate.ref_index, *ref_index),
         SubstrateKey::RegistrarIndex(registrar_index) => {
-            get_events_u32(&trees.substrate.registrar_index, *registrar_index)
+            get_events_u32(&trees.substrate.registrar_index, *registrar_index, query)
         }
         SubstrateKey::SessionIndex(session_index) => {
-            get_events_u32(&trees.substrate.session_index, *session_index)
+            get_events_u32(&trees.substrate.session_index, *session_index, query)
+        }
+        SubstrateKey::TipHash(tip_hash) => {
+            get_events_bytes32(&trees.substrate.tip_hash, tip_hash, query)
         }
-        SubstrateKey::TipHash(tip_hash) => get_events_bytes32(&trees.substrate.tip_hash, tip_hash),
         // Handle Ideal Network specific keys
         SubstrateKey::SubscriptionId(subscription_id) => {
-            get_events_bytes32(&trees.substrate.subscription_id, &subscription_id.0)
+            get_events_bytes32(&trees.substrate.subscription_id, &subscription_id.0, query)
         }
     }
 }
 
-pub fn process_msg_get_events<R: RuntimeIndexer>(
+/// Counts the events with the given key prefix within a block range.
+pub fn count_events(
+    tree: &Tree,
+    count: &Tree,
+    prefix: &[u8],
+    range: &BlockRange,
+) -> Result<u64, IndexError> {
+    match range.from_block.is_none() && range.to_block.is_none() {
+        // Use the maintained event count if the range is unbounded.
+        true => Ok(count.get(count_db_key(tree, prefix))?.map_or(0, |value| {
+            u64::from_be_bytes(value.as_slice().try_into().unwrap())
+        })),
+        false => {
+            let query = EventQuery {
+                range: *range,
+                ..EventQuery::default()
+            };
+            let mut event_count = 0;
+            if let Some(range) = event_range(prefix, &query) {
+                for key in tree.range(range).keys() {
+                    key?;
+                    event_count += 1;
+                }
+            }
+            Ok(event_count)
+        }
+    }
+}
+
+pub fn process_msg_count_events_substrate<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: &SubstrateKey,
+    range: &BlockRange,
+) -> Result<u64, IndexError> {
+    let count = &trees.count;
+    match key {
+        SubstrateKey::AccountId(account_id) => count_events(
+            &trees.substrate.account_id,
+            count,
+            account_id.as_ref(),
+            range,
+        ),
+        SubstrateKey::AccountIndex(account_index) => count_events(
+            &trees.substrate.account_index,
+            count,
+            &account_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::BountyIndex(bounty_index) => count_events(
+            &trees.substrate.bounty_index,
+            count,
+            &bounty_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::EraIndex(era_index) => count_events(
+            &trees.substrate.era_index,
+            count,
+            &era_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::MessageId(message_id) => count_events(
+            &trees.substrate.message_id,
+            count,
+            message_id.as_ref(),
+            range,
+        ),
+        SubstrateKey::PoolId(pool_id) => count_events(
+            &trees.substrate.pool_id,
+            count,
+            &pool_id.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::PreimageHash(preimage_hash) => count_events(
+            &trees.substrate.preimage_hash,
+            count,
+            preimage_hash.as_ref(),
+            range,
+        ),
+        SubstrateKey::ProposalHash(proposal_hash) => count_events(
+            &trees.substrate.proposal_hash,
+            count,
+            proposal_hash.as_ref(),
+            range,
+        ),
+        SubstrateKey::ProposalIndex(proposal_index) => count_events(
+            &trees.substrate.proposal_index,
+            count,
+            &proposal_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::RefIndex(ref_index) => count_events(
+            &trees.substrate.ref_index,
+            count,
+            &ref_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::RegistrarIndex(registrar_index) => count_events(
+            &trees.substrate.registrar_index,
+            count,
+            &registrar_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::SessionIndex(session_index) => count_events(
+            &trees.substrate.session_index,
+            count,
+            &session_index.to_be_bytes(),
+            range,
+        ),
+        SubstrateKey::TipHash(tip_hash) => {
+            count_events(&trees.substrate.tip_hash, count, tip_hash.as_ref(), range)
+        }
+        SubstrateKey::SubscriptionId(subscription_id) => count_events(
+            &trees.substrate.subscription_id,
+            count,
+            subscription_id.0.as_ref(),
+            range,
+        ),
+    }
+}
+
+pub fn process_msg_count_events<R: RuntimeIndexer>(
     trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
     key: Key<R::ChainKey>,
-) -> ResponseMessage<R::ChainKey> {
-    let events = match key {
+    range: BlockRange,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let count = match &key {
+        Key::Variant(pallet_id, variant_id) => count_events(
+            &trees.variant,
+            &trees.count,
+            &[*pallet_id, *variant_id],
+            &range,
+        ),
+        Key::Substrate(key) => process_msg_count_events_substrate::<R>(trees, key, &range),
+        Key::Chain(key) => key.count_key_events(&trees.chain, &trees.count, &range),
+    }?;
+    Ok(ResponseMessage::EventCount { key, count })
+}
+
+pub fn get_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: &Key<R::ChainKey>,
+    query: &EventQuery,
+) -> Result<Vec<Event>, StorageError> {
+    match key {
         Key::Variant(pallet_id, variant_id) => {
-            get_events_variant(&trees.variant, pallet_id, variant_id)
+            get_events_variant(&trees.variant, *pallet_id, *variant_id, query)
+        }
+        Key::Substrate(key) => process_msg_get_events_substrate::<R>(trees, key, query),
+        Key::Chain(key) => key.get_key_events(&trees.chain, query),
+    }
+}
+
+/// Retrieves the recorded hash of a block.
+pub fn get_block_hash(tree: &Tree, block_number: u32) -> Option<Bytes32> {
+    let block_hash = tree.get(block_number.to_be_bytes()).ok()??;
+    Some(Bytes32(block_hash.as_slice().try_into().ok()?))
+}
+
+/// Adds the recorded block hashes to events.
+pub fn add_block_hashes(tree: &Tree, events: &mut [Event]) {
+    for event in events {
+        event.block_hash = get_block_hash(tree, event.block_number);
+    }
+}
+
+/// Retrieves a page of events and the cursor for the next page, if there is one.
+fn get_events_page(
+    query: EventQuery,
+    get_events: impl Fn(&EventQuery) -> Result<Vec<Event>, StorageError>,
+) -> Result<(Vec<Event>, Option<Event>), StorageError> {
+    let limit = query.limit();
+    let mut events = get_events(&query)?;
+    events.truncate(limit);
+    if events.len() < limit {
+        return Ok((events, None));
+    }
+    // Check for one more event after the last one to determine if there is another page.
+    let last = events.last().cloned();
+    let more = EventQuery {
+        limit: Some(1),
+        before: match query.order {
+            Order::Asc => query.before,
+            Order::Desc => last.clone(),
+        },
+        after: match query.order {
+            Order::Asc => last.clone(),
+            Order::Desc => query.after,
+        },
+        ..query
+    };
+    let next = match get_events(&more)?.is_empty() {
+        true => None,
+        false => last,
+    };
+    Ok((events, next))
+}
+
+pub fn process_msg_get_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: Key<R::ChainKey>,
+    query: EventQuery,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let (mut events, next) = get_events_page(query, |query| get_events::<R>(trees, &key, query))?;
+    add_block_hashes(&trees.block_hash, &mut events);
+    Ok(ResponseMessage::Events { key, events, next })
+}
+
+pub fn process_msg_query<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    expr: KeyExpr<R::ChainKey>,
+    query: EventQuery,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    // Storage errors stop the query streams and are returned here.
+    let error = Cell::new(None);
+    let (mut events, next) = get_events_page(query, |query| {
+        let events = query_events::<R>(trees, &expr, query, &error)
+            .take(query.limit())
+            .collect();
+        match error.take() {
+            Some(error) => Err(error),
+            None => Ok(events),
+        }
+    })?;
+    add_block_hashes(&trees.block_hash, &mut events);
+    Ok(ResponseMessage::QueryEvents { events, next })
+}
+
+pub fn process_msg_get_event_payloads<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    events: Vec<Event>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let mut payloads = Vec::new();
+    for event in events.iter().take(EVENTS_LIMIT_MAX.try_into().unwrap()) {
+        let key = EventKey {
+            block_number: event.block_number.into(),
+            event_index: event.event_index.into(),
+        };
+        // Events without a stored payload are omitted.
+        if let Some(value) = trees.payload.get(key.as_bytes())? {
+            let (spec_version, payload) = value.split_at(4);
+            payloads.push(EventPayload {
+                block_number: event.block_number,
+                event_index: event.event_index,
+                spec_version: u32::from_be_bytes(spec_version.try_into().unwrap()),
+                payload: format!("0x{}", hex::encode(payload)),
+            });
         }
-        Key::Substrate(ref key) => process_msg_get_events_substrate::<R>(trees, key),
-        Key::Chain(ref key) => key.get_key_events(&trees.chain),
+    }
+    Ok(ResponseMessage::EventPayloads(payloads))
+}
+
+pub fn process_msg_tree_stats<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    // Span keys are the last block of the span. Values start with the first block.
+    let mut stats = vec![TreeStats::collect(&trees.span, |key, value| {
+        Some((block_number_at(value, 0)?, block_number_at(key, 0)?))
+    })?];
+    let event_key_blocks = |key: &[u8], _: &[u8]| {
+        event_key_block_number(key).map(|block_number| (block_number, block_number))
+    };
+    stats.push(TreeStats::collect(&trees.variant, event_key_blocks)?);
+    stats.push(TreeStats::collect(&trees.count, |_, _| None)?);
+    // Keys of these trees start with the block number.
+    for tree in [
+        &trees.payload,
+        &trees.block_hash,
+        &trees.unfinalized,
+        &trees.failed,
+    ] {
+        stats.push(TreeStats::collect(tree, |key, _| {
+            block_number_at(key, 0).map(|block_number| (block_number, block_number))
+        })?);
+    }
+    for tree in trees.substrate.trees() {
+        stats.push(TreeStats::collect(tree, event_key_blocks)?);
+    }
+    stats.extend(trees.chain.stats()?);
+    Ok(ResponseMessage::TreeStats(stats))
+}
+
+pub fn process_msg_failed_blocks<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let mut failed_blocks = Vec::new();
+    for entry in &trees.failed {
+        let (key, value) = entry?;
+        failed_blocks.push(FailedBlock {
+            block_number: u32::from_be_bytes(key.as_slice().try_into().unwrap()),
+            error: String::from_utf8_lossy(&value).into_owned(),
+        });
+    }
+    Ok(ResponseMessage::FailedBlocks(failed_blocks))
+}
+
+/// Asks the indexer to re-index blocks `start` to `end`. The range is clipped to the indexed
+/// blocks and can't be longer than `REINDEX_MAX_BLOCKS`.
+pub fn process_msg_reindex_blocks<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    start: u32,
+    end: u32,
+    sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let (Some((_, first)), Some((last, _))) = (trees.span.first()?, trees.span.last()?) else {
+        return Err(IndexError::InvalidRequest);
     };
-    ResponseMessage::Events { key, events }
+    let start = start.max(SpanDbValue::read_from(&first).unwrap().start.into());
+    let end = end.min(u32::from_be_bytes(last.as_slice().try_into().unwrap()));
+    if start > end || end - start >= REINDEX_MAX_BLOCKS {
+        return Err(IndexError::InvalidRequest);
+    }
+    sub_tx
+        .send(SubscriptionMessage::ReindexBlocks { start, end })
+        .unwrap();
+    Ok(ResponseMessage::Reindexing { start, end })
 }
 
 pub fn process_msg_subscribe_events<R: RuntimeIndexer>(
     key: Key<R::ChainKey>,
+    range: BlockRange,
+    id: Option<RequestId>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> ResponseMessage<R::ChainKey> {
     let msg = SubscriptionMessage::SubscribeEvents {
         key,
+        range,
+        id,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
@@ -201,11 +642,13 @@ pub fn process_msg_subscribe_events<R: RuntimeIndexer>(
 
 pub fn process_msg_unsubscribe_events<R: RuntimeIndexer>(
     key: Key<R::ChainKey>,
+    id: Option<RequestId>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> ResponseMessage<R::ChainKey> {
     let msg = SubscriptionMessage::UnsubscribeEvents {
         key,
+        id,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
@@ -215,61 +658,189 @@ pub fn process_msg_unsubscribe_events<R: RuntimeIndexer>(
 pub async fn process_msg<R: RuntimeIndexer>(
     rpc: &LegacyRpcMethods<R::RuntimeConfig>,
     trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    msg: RequestMessage<R::ChainKey>,
+    request: Request<R::ChainKey>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
-    Ok(match msg {
-        RequestMessage::Status => process_msg_status::<R>(&trees.span),
+    let id = request.id;
+    Ok(match request.msg {
+        RequestMessage::Status => process_msg_status::<R>(trees),
+        RequestMessage::Target => process_msg_target::<R>(trees)?,
         RequestMessage::SubscribeStatus => {
-            process_msg_subscribe_status::<R>(sub_tx, sub_response_tx)
+            process_msg_subscribe_status::<R>(id, sub_tx, sub_response_tx)
         }
         RequestMessage::UnsubscribeStatus => {
-            process_msg_unsubscribe_status::<R>(sub_tx, sub_response_tx)
+            process_msg_unsubscribe_status::<R>(None, sub_tx, sub_response_tx)
         }
         RequestMessage::Variants => process_msg_variants::<R>(rpc).await?,
-        RequestMessage::GetEvents { key } => process_msg_get_events::<R>(trees, key),
-        RequestMessage::SubscribeEvents { key } => {
-            process_msg_subscribe_events::<R>(key, sub_tx, sub_response_tx)
+        RequestMessage::GetEvents { key, query } => process_msg_get_events::<R>(trees, key, query)?,
+        RequestMessage::Query { expr, query } => process_msg_query::<R>(trees, expr, query)?,
+        RequestMessage::SubscribeEvents { key, range } => {
+            process_msg_subscribe_events::<R>(key, range, id, sub_tx, sub_response_tx)
         }
         RequestMessage::UnsubscribeEvents { key } => {
-            process_msg_unsubscribe_events::<R>(key, sub_tx, sub_response_tx)
+            process_msg_unsubscribe_events::<R>(key, None, sub_tx, sub_response_tx)
+        }
+        RequestMessage::CountEvents { key, range } => {
+            process_msg_count_events::<R>(trees, key, range)?
+        }
+        RequestMessage::GetEventPayloads { events } => {
+            process_msg_get_event_payloads::<R>(trees, events)?
         }
         RequestMessage::SizeOnDisk => ResponseMessage::SizeOnDisk(trees.root.size_on_disk()?),
+        RequestMessage::TreeStats => process_msg_tree_stats::<R>(trees)?,
+        RequestMessage::FailedBlocks => process_msg_failed_blocks::<R>(trees)?,
     })
 }
 
+/// Creates a request message of the given type from its fields.
+pub fn request_from_params<CK: IndexKey + for<'a> Deserialize<'a>>(
+    request_type: &str,
+    mut params: Map<String, Value>,
+) -> Result<RequestMessage<CK>, IndexError> {
+    params.insert("type".to_string(), request_type.into());
+    Ok(serde_json::from_value(Value::Object(params))?)
+}
+
+/// Websocket subprotocol for CBOR encoding
+pub const CBOR_PROTOCOL: &str = "cbor";
+
+/// Parses a request from a websocket message. Binary messages are encoded with CBOR.
+pub fn parse_request<CK: IndexKey + for<'a> Deserialize<'a>>(
+    msg: &tungstenite::Message,
+) -> Result<Request<CK>, IndexError> {
+    match msg {
+        tungstenite::Message::Binary(bytes) => Ok(ciborium::from_reader(bytes.as_slice())?),
+        _ => {
+            let text = msg.to_text().map_err(|_| IndexError::ParseError)?;
+            Ok(serde_json::from_str(text)?)
+        }
+    }
+}
+
+/// Encodes a response as a websocket message, either binary CBOR or text JSON.
+pub fn encode_response<CK: IndexKey + Serialize>(
+    response: &Response<CK>,
+    binary: bool,
+) -> tungstenite::Message {
+    match binary {
+        true => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(response, &mut bytes).unwrap();
+            tungstenite::Message::Binary(bytes)
+        }
+        false => tungstenite::Message::Text(serde_json::to_string(response).unwrap()),
+    }
+}
+
+/// Processes a request in the native protocol and returns the response.
+pub async fn process_request<R: RuntimeIndexer>(
+    rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    msg: &tungstenite::Message,
+    sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+) -> Response<R::ChainKey> {
+    let (id, response_msg) = match parse_request(msg) {
+        Ok(request) => (
+            request.id.clone(),
+            process_msg::<R>(rpc, trees, request, sub_tx, sub_response_tx).await,
+        ),
+        Err(error) => (None, Err(error)),
+    };
+    // Send errors to the client instead of dropping the connection.
+    Response {
+        id,
+        msg: response_msg.unwrap_or_else(|error| {
+            error!("{}", error);
+            error.into()
+        }),
+    }
+}
+
+/// Handshake callback that accepts the CBOR subprotocol and records whether it was requested
+struct CborProtocol<'a>(&'a mut bool);
+
+impl Callback for CborProtocol<'_> {
+    fn on_request(
+        self,
+        request: &WsRequest,
+        mut response: WsResponse,
+    ) -> Result<WsResponse, ErrorResponse> {
+        let protocols = request.headers().get(SEC_WEBSOCKET_PROTOCOL);
+        if let Some(protocols) = protocols.and_then(|protocols| protocols.to_str().ok())
+            && protocols
+                .split(',')
+                .any(|protocol| protocol.trim() == CBOR_PROTOCOL)
+        {
+            *self.0 = true;
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, CBOR_PROTOCOL.parse().unwrap());
+        }
+        Ok(response)
+    }
+}
+
 async fn handle_connection<R: RuntimeIndexer>(
-    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    rpc_rx: Receiver<LegacyRpcMethods<R::RuntimeConfig>>,
     raw_stream: TcpStream,
     addr: SocketAddr,
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
     sub_tx: UnboundedSender<SubscriptionMessage<R::ChainKey>>,
 ) -> Result<(), IndexError> {
     info!("Incoming TCP connection from: {}", addr);
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream).await?;
+    // Clients can choose CBOR encoding for subscription notifications at handshake.
+    let mut cbor_protocol = false;
+    let callback = CborProtocol(&mut cbor_protocol);
+    let ws_stream = tokio_tungstenite::accept_hdr_async(raw_stream, callback).await?;
     info!("WebSocket connection established: {}", addr);
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
     // Create the channel for the substrate thread to send event messages to this thread.
     let (sub_events_tx, mut sub_events_rx) = unbounded_channel();
+    // JSON-RPC subscriptions have their own channel so notifications can be sent in JSON-RPC format.
+    let (jsonrpc_sub_events_tx, mut jsonrpc_sub_events_rx) = unbounded_channel();
+    let mut jsonrpc_subs = JsonRpcSubscriptions::default();
+    // Without a subprotocol, notifications use the encoding of the most recent request.
+    let mut binary = cbor_protocol;
 
     loop {
         tokio::select! {
             Some(Ok(msg)) = ws_receiver.next() => {
                 if msg.is_text() || msg.is_binary() {
-                    match serde_json::from_str(msg.to_text()?) {
-                        Ok(request_json) => {
-                            let response_msg = process_msg::<R>(&rpc, &trees, request_json, &sub_tx, &sub_events_tx).await?;
-                            let response_json = serde_json::to_string(&response_msg).unwrap();
-                            ws_sender.send(tungstenite::Message::Text(response_json)).await?;
-                        },
-                        Err(error) => error!("{}", error),
+                    // Use the most recent connection to the node.
+                    let rpc = rpc_rx.borrow().clone();
+                    let text = match msg.is_text() {
+                        true => Some(msg.to_text()?),
+                        false => None,
+                    };
+                    let response_msg = match text.map(serde_json::from_str::<Value>) {
+                        Some(Ok(value)) if is_jsonrpc(&value) => {
+                            process_jsonrpc::<R>(&rpc, &trees, value, &sub_tx, &jsonrpc_sub_events_tx, &mut jsonrpc_subs).await
+                                .map(tungstenite::Message::Text)
+                        }
+                        Some(Err(_)) if text.is_some_and(is_jsonrpc_text) => {
+                            Some(tungstenite::Message::Text(jsonrpc_parse_error()))
+                        }
+                        _ => {
+                            if !cbor_protocol {
+                                binary = msg.is_binary();
+                            }
+                            let response = process_request::<R>(&rpc, &trees, &msg, &sub_tx, &sub_events_tx).await;
+                            Some(encode_response(&response, msg.is_binary()))
+                        }
+                    };
+                    if let Some(response_msg) = response_msg {
+                        ws_sender.send(response_msg).await?;
                     }
                 }
             },
             Some(msg) = sub_events_rx.recv() => {
-                let response_json = serde_json::to_string(&msg).unwrap();
+                ws_sender.send(encode_response(&msg, binary)).await?;
+            },
+            Some(msg) = jsonrpc_sub_events_rx.recv() => {
+                let response_json = jsonrpc_notification(msg);
                 ws_sender.send(tungstenite::Message::Text(response_json)).await?;
             },
         }
@@ -278,7 +849,7 @@ async fn handle_connection<R: RuntimeIndexer>(
 
 pub async fn websockets_listen<R: RuntimeIndexer + 'static>(
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    rpc_rx: Receiver<LegacyRpcMethods<R::RuntimeConfig>>,
     port: u16,
     mut exit_rx: Receiver<bool>,
     sub_tx: UnboundedSender<SubscriptionMessage<R::ChainKey>>,
@@ -301,7 +872,7 @@ pub async fn websockets_listen<R: RuntimeIndexer + 'static>(
             }
             Ok((stream, addr)) = listener.accept() => {
                 tokio::spawn(handle_connection::<R>(
-                    rpc.clone(),
+                    rpc_rx.clone(),
                     stream,
                     addr,
                     trees.clone(),